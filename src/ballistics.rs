@@ -4,11 +4,11 @@ use cgmath::Vector3;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ballistics {
-    mass: f64, // kg
-    diameter: f64, // m
-    muzzle_speed: f64, // m/s
-    drag: f64, // coefficient
-    krupp: f64,
+    pub mass: f64, // kg
+    pub diameter: f64, // m
+    pub muzzle_speed: f64, // m/s
+    pub drag: f64, // coefficient
+    pub krupp: f64,
 }
 
 impl Ballistics {
@@ -96,45 +96,72 @@ impl Ballistics {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// The game's dispersion model. The radii and `ideal_distance` are in the
+/// game's 30m units, the other distances in meters.
+///
+/// The horizontal radius grows linearly with range, from `min_radius` at
+/// point blank to `ideal_radius` at `ideal_distance`, except that inside
+/// `taper_distance` it shrinks linearly to nothing. The vertical radius is the
+/// horizontal radius times a ratio which goes linearly from
+/// `radius_on_zero` at point blank to `radius_on_delim` at `delim` times the
+/// maximum range, and from there to `radius_on_max` at maximum range.
+#[derive(new, Clone, Serialize, Deserialize)]
 pub struct Dispersion {
-    horizontal: f64,
-    vertical: f64,
-    maxrange: f64,
-    sigma: f64,
+    pub ideal_radius: f64,
+    pub min_radius: f64,
+    pub ideal_distance: f64,
+    pub taper_distance: f64,
+    pub radius_on_zero: f64,
+    pub radius_on_delim: f64,
+    pub radius_on_max: f64,
+    pub delim: f64,
+    pub max_range: f64,
+    /// Shots are normally distributed and cut off at this many standard
+    /// deviations, which is where the dispersion radius lies
+    pub sigma: f64,
 }
 
-fn bounded_gauss(sigma: f64) -> f64 {
-    let normal = Normal::new(0.0, sigma).unwrap();
+/// Samples a standard normal distribution truncated to ±`sigma`, scaled so
+/// the cut off lies at ±1
+fn truncated_gauss(sigma: f64) -> f64 {
+    let normal = Normal::new(0.0, 1.0).unwrap();
     loop {
-        let v = normal.sample(&mut rand::thread_rng());
-        if v > -0.5 && v < 0.5 {
-            return v;
+        let v: f64 = normal.sample(&mut rand::thread_rng());
+        if v.abs() <= sigma {
+            return v / sigma;
         }
     }
 }
 
 impl Dispersion {
-    pub fn new(
-        horizontal: f64,
-        vertical: f64,
-        maxrange: f64,
-        sigma: f64,
-    ) -> Dispersion {
-        Dispersion {
-            horizontal: horizontal,
-            vertical: vertical,
-            maxrange: maxrange,
-            sigma: sigma,
+    fn untapered_horizontal(&self, range: f64) -> f64 {
+        range * (self.ideal_radius - self.min_radius) / self.ideal_distance + self.min_radius * 30.0
+    }
+
+    /// The horizontal dispersion radius at the given range, in meters
+    pub fn horizontal(&self, range: f64) -> f64 {
+        if range < self.taper_distance {
+            self.untapered_horizontal(self.taper_distance) * range / self.taper_distance
+        } else {
+            self.untapered_horizontal(range)
         }
     }
 
+    /// The vertical dispersion radius at the given range, in meters
+    pub fn vertical(&self, range: f64) -> f64 {
+        let delim_distance = self.max_range * self.delim;
+        let ratio = if range < delim_distance {
+            self.radius_on_zero + (self.radius_on_delim - self.radius_on_zero) * range / delim_distance
+        } else {
+            self.radius_on_delim + (self.radius_on_max - self.radius_on_delim) * (range - delim_distance) / (self.max_range - delim_distance)
+        };
+        self.horizontal(range) * ratio
+    }
+
     /// Returns a randomly generated offset based on dispersion
     pub fn generate_offset(&self, azimuth: f64, range: f64) -> Vector3<f64> {
-        let distance_factor = range / self.maxrange;
-        let x = self.horizontal * bounded_gauss(self.sigma) * distance_factor;
-        let y = self.vertical * bounded_gauss(self.sigma) * distance_factor;
-        //info!("{} {} {} {} {},{}", self.horizontal, self.vertical, distance_factor, self.sigma, x, y);
+        let x = self.horizontal(range) * truncated_gauss(self.sigma);
+        let y = self.vertical(range) * truncated_gauss(self.sigma);
         Vector3::new(
             x * deg2rad(azimuth).cos() - y * deg2rad(azimuth).sin(),
             0.0,
@@ -142,3 +169,85 @@ impl Dispersion {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    /// A cruiser's guns, as in the README's JSON example
+    fn cruiser() -> Dispersion {
+        Dispersion::new(8.0, 1.0, 1000.0, 5000.0, 0.2, 0.5, 0.6, 0.5, 14950.0, 2.0)
+    }
+
+    /// Guns with no taper, so point blank is `min_radius`
+    fn untapered() -> Dispersion {
+        Dispersion::new(10.0, 2.8, 1000.0, 0.0, 0.2, 0.4, 0.8, 0.5, 20000.0, 1.8)
+    }
+
+    #[test]
+    fn horizontal_dispersion() {
+        let cruiser = cruiser();
+        assert_close(cruiser.horizontal(0.0), 0.0);
+        assert_close(cruiser.horizontal(2500.0), 32.5);
+        assert_close(cruiser.horizontal(5000.0), 65.0);
+        assert_close(cruiser.horizontal(10000.0), 100.0);
+        assert_close(cruiser.horizontal(14950.0), 134.65);
+        // ideal_radius at ideal_distance, in 30m units
+        assert_close(cruiser.horizontal(30000.0), 8.0 * 30.0);
+
+        let untapered = untapered();
+        assert_close(untapered.horizontal(0.0), 84.0);
+        assert_close(untapered.horizontal(10000.0), 156.0);
+        assert_close(untapered.horizontal(20000.0), 228.0);
+    }
+
+    /// Yamato's 460mm guns have the usual battleship `idealRadius` of 10 and
+    /// `minRadius` of 2.8, and a range of 26.63km, where the game gives their
+    /// maximum dispersion as 275m
+    #[test]
+    fn matches_the_published_dispersion_of_yamato() {
+        let yamato = Dispersion::new(10.0, 2.8, 1000.0, 0.0, 0.2, 0.5, 0.6, 0.5, 26630.0, 2.1);
+        assert!((yamato.horizontal(26630.0) - 275.0).abs() < 1.0, "{}", yamato.horizontal(26630.0));
+    }
+
+    #[test]
+    fn vertical_dispersion() {
+        let cruiser = cruiser();
+        assert_close(cruiser.vertical(0.0), 0.0);
+        assert_close(cruiser.vertical(5000.0), 65.0 * (0.2 + 0.3 * 5000.0 / 7475.0));
+        assert_close(cruiser.vertical(7475.0), 82.325 * 0.5);
+        assert_close(cruiser.vertical(10000.0), 100.0 * (0.5 + 0.1 * 2525.0 / 7475.0));
+        assert_close(cruiser.vertical(14950.0), 134.65 * 0.6);
+
+        let untapered = untapered();
+        assert_close(untapered.vertical(0.0), 84.0 * 0.2);
+        assert_close(untapered.vertical(10000.0), 156.0 * 0.4);
+        assert_close(untapered.vertical(20000.0), 228.0 * 0.8);
+    }
+
+    #[test]
+    fn truncated_gauss_stays_in_bounds() {
+        for &sigma in [0.5, 1.0, 2.0, 2.8].iter() {
+            let samples: Vec<_> = (0..10000).map(|_| { truncated_gauss(sigma) }).collect();
+            assert!(samples.iter().all(|v| { v.abs() <= 1.0 }));
+            // Scaled to the cut off, rather than stuck near the middle
+            assert!(samples.iter().any(|v| { v.abs() > 0.5 }));
+            assert!(samples.iter().any(|v| { *v < 0.0 }) && samples.iter().any(|v| { *v > 0.0 }));
+        }
+    }
+
+    #[test]
+    fn offsets_stay_within_the_dispersion_radii() {
+        let cruiser = cruiser();
+        for _ in 0..10000 {
+            let offset = cruiser.generate_offset(0.0, 10000.0);
+            assert!(offset.x.abs() <= cruiser.horizontal(10000.0) + 1e-9);
+            assert!(offset.z.abs() <= cruiser.vertical(10000.0) + 1e-9);
+            assert_eq!(offset.y, 0.0);
+        }
+    }
+}
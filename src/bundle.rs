@@ -0,0 +1,227 @@
+use serde_derive::{Serialize, Deserialize};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::cache::{Cache, CacheEntry, url_hash};
+use crate::error::{Error, Result};
+use crate::import::backfill_vehicle_index;
+use crate::shiplist::{country_url, parse_country_page};
+use crate::util::{file_header, read_file_header, sha256};
+use crate::version::GameVersion;
+
+/// Bundle files start with this, followed by the little-endian format
+/// version, followed by a gzipped bincode `Bundle`.
+const BUNDLE_MAGIC: &[u8; 8] = b"WOWSCBDL";
+const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct BundleEntry {
+    entry: CacheEntry,
+    /// Hex SHA-256 of `data`
+    sha256: String,
+    /// Uncompressed contents
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    game_version: GameVersion,
+    entries: Vec<BundleEntry>,
+}
+
+/// Which parts of the cache to put in a bundle. If both lists are empty,
+/// everything goes in.
+#[derive(Default)]
+pub struct BundleFilter {
+    pub nations: Vec<String>,
+    pub vehicles: Vec<String>,
+}
+
+impl BundleFilter {
+    fn is_empty(&self) -> bool {
+        self.nations.is_empty() && self.vehicles.is_empty()
+    }
+
+    /// Resolves the filter to a set of cache hashes, using the cached nation
+    /// pages to find which vehicles belong to each nation
+    fn hashes(&self, cache: &Cache, version: &GameVersion) -> Result<HashSet<String>> {
+        let mut vehicles = self.vehicles.clone();
+        let mut hashes = HashSet::new();
+        for nation in self.nations.iter() {
+            let hash = url_hash(&country_url(nation));
+            if cache.backfill(&country_url(nation), None, None)?.is_none() {
+                return Err(Error::NotCached { url: country_url(nation), hash: hash });
+            }
+            vehicles.extend(parse_country_page(&cache.read(&hash)?, nation).into_iter().map(|entry| { entry.id }));
+            hashes.insert(hash);
+        }
+        for vehicle in vehicles.iter() {
+            backfill_vehicle_index(cache, version, vehicle)?;
+            hashes.extend(cache.vehicle_hashes(vehicle));
+        }
+        Ok(hashes)
+    }
+}
+
+/// Writes the matching cache entries to a bundle file. Returns how many
+/// entries were written.
+pub fn export_bundle<P: AsRef<Path>>(cache: &Cache, version: &GameVersion, filter: &BundleFilter, path: P) -> Result<usize> {
+    let wanted = if filter.is_empty() { None } else { Some(filter.hashes(cache, version)?) };
+
+    let mut entries = vec!();
+    for entry in cache.entries() {
+        if let Some(wanted) = &wanted {
+            if !wanted.contains(&entry.hash) {
+                continue;
+            }
+        }
+        if !cache.contains(&entry.hash) {
+            warn!("Index has {} but the file is missing, skipping it", entry.url);
+            continue;
+        }
+        let data = cache.read_bytes(&entry.hash)?;
+        entries.push(BundleEntry {
+            entry: entry,
+            sha256: sha256(&data),
+            data: data,
+        });
+    }
+    entries.sort_by(|a, b| { a.entry.hash.cmp(&b.entry.hash) });
+
+    let count = entries.len();
+    write_bundle(&Bundle {
+        game_version: version.clone(),
+        entries: entries,
+    }, path)?;
+    Ok(count)
+}
+
+fn write_bundle<P: AsRef<Path>>(bundle: &Bundle, path: P) -> Result<()> {
+    let mut f = fs::File::create(path)?;
+    f.write_all(&file_header(BUNDLE_MAGIC, BUNDLE_FORMAT_VERSION))?;
+    let mut encoder = GzEncoder::new(f, Compression::default());
+    bincode::serialize_into(&mut encoder, bundle)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// How an import went
+#[derive(Default, Debug)]
+pub struct BundleImport {
+    pub added: usize,
+    /// Entries we already had a copy of which was at least as new
+    pub skipped: usize,
+}
+
+/// Merges a bundle file into the cache. Entries we already have are only
+/// replaced if the bundle's copy was fetched more recently.
+pub fn import_bundle<P: AsRef<Path>>(cache: &Cache, version: &GameVersion, path: P) -> Result<BundleImport> {
+    let path = path.as_ref();
+    let bad_bundle = |reason: String| {
+        Error::Decode { url: path.display().to_string(), reason: reason }
+    };
+
+    let mut f = fs::File::open(path)?;
+    read_file_header(&mut f, path, "cache bundle", BUNDLE_MAGIC, BUNDLE_FORMAT_VERSION)?;
+
+    let bundle: Bundle = bincode::deserialize_from(GzDecoder::new(f)).map_err(|e| {
+        bad_bundle(e.to_string())
+    })?;
+    if &bundle.game_version != version {
+        return Err(bad_bundle(format!(
+            "Bundle is for game version {}, not {}",
+            bundle.game_version, version
+        )));
+    }
+
+    // Check everything before touching the cache, so a corrupt bundle
+    // doesn't get half imported
+    for entry in bundle.entries.iter() {
+        if !entry.entry.has_valid_hash() {
+            return Err(bad_bundle(format!("Bad hash {:?} for {}", entry.entry.hash, entry.entry.url)));
+        }
+        if sha256(&entry.data) != entry.sha256 {
+            return Err(bad_bundle(format!("Checksum mismatch for {}", entry.entry.url)));
+        }
+    }
+
+    let existing: HashMap<_, _> = cache.entries().into_iter().map(|entry| {
+        (entry.hash.clone(), entry)
+    }).collect();
+    let mut result = BundleImport::default();
+    for entry in bundle.entries {
+        let newer = match existing.get(&entry.entry.hash) {
+            Some(existing) => entry.entry.fetched_at > existing.fetched_at,
+            None => true,
+        };
+        if newer || !cache.contains(&entry.entry.hash) {
+            cache.insert(entry.entry, &entry.data)?;
+            result.added += 1;
+        } else {
+            result.skipped += 1;
+        }
+    }
+    info!("Imported {} cache entries, skipped {} we already had", result.added, result.skipped);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const URL: &str = "https://gamemodels3d.com/games/worldofwarships/vehicles/pasd014";
+
+    fn bundle_with(entry: CacheEntry, data: &[u8]) -> Bundle {
+        Bundle {
+            game_version: GameVersion::current(),
+            entries: vec![BundleEntry { entry: entry, sha256: sha256(data), data: data.to_vec() }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("bundle");
+        let source = Cache::open(dir.join("source"));
+        source.insert(CacheEntry::new(URL, None, None, 200, 4), b"page").unwrap();
+        let path = dir.join("cache.bundle");
+        assert_eq!(export_bundle(&source, &GameVersion::current(), &BundleFilter::default(), &path).unwrap(), 1);
+
+        let target = Cache::open(dir.join("target"));
+        let result = import_bundle(&target, &GameVersion::current(), &path).unwrap();
+        assert_eq!(result.added, 1);
+        assert_eq!(target.read(&url_hash(URL)).unwrap(), "page");
+    }
+
+    #[test]
+    fn rejects_paths_as_hashes() {
+        let dir = TempDir::new("bundle");
+        let mut entry = CacheEntry::new(URL, None, None, 200, 4);
+        entry.hash = "../../escaped".to_string();
+        let path = dir.join("cache.bundle");
+        write_bundle(&bundle_with(entry, b"evil"), &path).unwrap();
+
+        let cache = Cache::open(dir.join("a/b/cache"));
+        assert!(import_bundle(&cache, &GameVersion::current(), &path).is_err());
+        assert!(!dir.join("a/escaped").exists());
+    }
+
+    #[test]
+    fn rejects_hashes_of_other_urls() {
+        let dir = TempDir::new("bundle");
+        let mut entry = CacheEntry::new(URL, None, None, 200, 4);
+        entry.hash = url_hash("https://gamemodels3d.com/games/worldofwarships/vehicles/pasb006");
+        let path = dir.join("cache.bundle");
+        write_bundle(&bundle_with(entry, b"evil"), &path).unwrap();
+
+        let cache = Cache::open(dir.join("cache"));
+        assert!(import_bundle(&cache, &GameVersion::current(), &path).is_err());
+        assert!(cache.entries().is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+use serde_derive::{Serialize, Deserialize};
+use regex::Regex;
+use log::{warn, info};
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::{Error, Result};
+use crate::util::{sha256, write_atomically};
+
+const INDEX_FILE: &str = "index.jsonl";
+
+/// The first two bytes of any gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Metadata about a single file in the cache
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub hash: String,
+    pub url: String,
+    /// Only set for entries fetched through `download_with_params`
+    pub view: Option<String>,
+    pub params: Option<String>,
+    pub fetched_at: u64, // seconds since the epoch
+    pub status: u16,
+    pub size: usize, // bytes, uncompressed
+    /// The Content-Type the server sent, if any
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// Whether the file on disk is gzipped. Entries from before compression
+    /// are stored as plain text.
+    #[serde(default)]
+    pub compressed: bool,
+    /// Validators for conditional requests, if the server sent them
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+}
+
+impl CacheEntry {
+    pub fn new(url: &str, view: Option<&str>, params: Option<&str>, status: u16, size: usize) -> CacheEntry {
+        CacheEntry {
+            hash: entry_hash(url, view, params),
+            url: url.to_string(),
+            view: view.map(|s| { s.to_string() }),
+            params: params.map(|s| { s.to_string() }),
+            fetched_at: now(),
+            status: status,
+            size: size,
+            content_type: None,
+            compressed: false,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Whether `hash` is really the hash of this entry's URL, view and params.
+    /// Entries from elsewhere (like bundles) need checking, since the hash is
+    /// used as a file name.
+    pub fn has_valid_hash(&self) -> bool {
+        is_hash(&self.hash) && self.hash == entry_hash(&self.url, self.view.as_deref(), self.params.as_deref())
+    }
+}
+
+fn entry_hash(url: &str, view: Option<&str>, params: Option<&str>) -> String {
+    match (view, params) {
+        (Some(view), Some(params)) => { params_hash(url, view, params) }
+        _ => { url_hash(url) }
+    }
+}
+
+/// Whether `hash` looks like one of our hashes: 64 lowercase hex digits
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| { c.is_ascii_digit() || ('a'..='f').contains(&c) })
+}
+
+pub fn url_hash(url: &str) -> String {
+    sha256(url.as_bytes())
+}
+
+pub fn params_hash(url: &str, view: &str, params: &str) -> String {
+    sha256((url.to_string() + view + params).as_bytes())
+}
+
+/// The armor model files an armor view refers to
+pub fn armor_models(armor_view: &str) -> Vec<String> {
+    let model_re = Regex::new(r#""model"\s*:\s*"([^"]+)""#).unwrap();
+    model_re.captures_iter(armor_view).map(|capture| { capture[1].replace("\\/", "/") }).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| { d.as_secs() }).unwrap_or(0)
+}
+
+/// A directory of downloaded files, named by the SHA-256 of their URL, along
+/// with an index recording where each file came from.
+///
+/// The index is an append-only list of JSON lines, the last line for a given
+/// hash wins. It gets compacted whenever entries are expired.
+pub struct Cache {
+    root: PathBuf,
+    index: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Cache {
+    pub fn open<P: AsRef<Path>>(root: P) -> Cache {
+        let root = root.as_ref().to_path_buf();
+        if let Err(e) = fs::create_dir_all(&root) {
+            warn!("Couldn't create cache directory {:?}: {}", root, e);
+        }
+
+        let mut index = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(root.join(INDEX_FILE)) {
+            for line in contents.lines() {
+                match serde_json::from_str::<CacheEntry>(line) {
+                    Ok(entry) => { index.insert(entry.hash.clone(), entry); }
+                    Err(e) => { warn!("Skipping bad cache index line {:?}: {}", line, e); }
+                }
+            }
+        }
+
+        Cache {
+            root: root,
+            index: Mutex::new(index),
+        }
+    }
+
+    pub fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.path(hash).exists()
+    }
+
+    /// Returns the index entry for the given hash. Files cached before the
+    /// index existed won't have one.
+    pub fn entry(&self, hash: &str) -> Option<CacheEntry> {
+        self.index.lock().unwrap().get(hash).cloned()
+    }
+
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        self.index.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Reads the raw bytes of a file, decompressing it if needed
+    pub fn read_bytes(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        let contents = fs::read(self.path(hash))?;
+        // Sniff rather than trusting the index, since uncompressed entries
+        // were always text, and text can't start with the gzip magic
+        if contents.starts_with(&GZIP_MAGIC) {
+            let mut body = vec!();
+            GzDecoder::new(&contents[..]).read_to_end(&mut body)?;
+            Ok(body)
+        } else {
+            Ok(contents)
+        }
+    }
+
+    /// Reads a file as text, failing if it isn't valid UTF-8
+    pub fn read(&self, hash: &str) -> Result<String> {
+        let body = self.read_bytes(hash)?;
+        String::from_utf8(body).map_err(|e| {
+            let url = self.index.lock().unwrap().get(hash).map(|entry| { entry.url.clone() });
+            Error::Decode { url: url.unwrap_or_else(|| { hash.to_string() }), reason: e.to_string() }
+        })
+    }
+
+    /// Stores `body` (compressed) under the entry's hash, and records the
+    /// entry in the index
+    pub fn insert(&self, mut entry: CacheEntry, body: &[u8]) -> std::io::Result<()> {
+        let mut encoder = GzEncoder::new(vec!(), Compression::default());
+        encoder.write_all(body)?;
+        let compressed = encoder.finish()?;
+        entry.size = body.len();
+        entry.compressed = true;
+
+        // Two threads may be fetching the same URL at once
+        write_atomically(self.path(&entry.hash), &compressed)?;
+
+        let mut index = self.index.lock().unwrap();
+        self.append_to_index(&entry)?;
+        index.insert(entry.hash.clone(), entry);
+        Ok(())
+    }
+
+    /// Returns the index entry for a URL (or a view of it), first adding one
+    /// if the file was cached before the index existed. `None` if the file
+    /// isn't cached at all.
+    pub fn backfill(&self, url: &str, view: Option<&str>, params: Option<&str>) -> std::io::Result<Option<CacheEntry>> {
+        let hash = entry_hash(url, view, params);
+        if let Some(entry) = self.entry(&hash) {
+            return Ok(Some(entry));
+        }
+        let path = self.path(&hash);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => { return Ok(None); }
+        };
+        let mut entry = CacheEntry::new(url, view, params, 200, self.read_bytes(&hash)?.len());
+        entry.fetched_at = metadata.modified().ok()
+            .and_then(|modified| { modified.duration_since(UNIX_EPOCH).ok() })
+            .map(|d| { d.as_secs() })
+            .unwrap_or(0);
+        entry.compressed = fs::read(&path)?.starts_with(&GZIP_MAGIC);
+        info!("Added {} to the cache index", url);
+
+        let mut index = self.index.lock().unwrap();
+        self.append_to_index(&entry)?;
+        index.insert(hash, entry.clone());
+        Ok(Some(entry))
+    }
+
+    /// Marks an entry as freshly fetched, e.g. after the server told us it
+    /// hasn't changed
+    pub fn touch(&self, hash: &str) -> std::io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.get_mut(hash) {
+            entry.fetched_at = now();
+            self.append_to_index(entry)?;
+        }
+        Ok(())
+    }
+
+    fn append_to_index(&self, entry: &CacheEntry) -> std::io::Result<()> {
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(self.root.join(INDEX_FILE))?;
+        f.write_all((serde_json::to_string(entry)? + "\n").as_bytes())
+    }
+
+    /// Expires every entry fetched more than `age` ago
+    pub fn expire_older_than(&self, age: Duration) -> std::io::Result<usize> {
+        let cutoff = now().saturating_sub(age.as_secs());
+        self.expire_where(|entry| { entry.fetched_at < cutoff })
+    }
+
+    /// Expires every entry whose URL matches the pattern, e.g. `/armor/`
+    pub fn expire_matching(&self, pattern: &Regex) -> std::io::Result<usize> {
+        self.expire_where(|entry| { pattern.is_match(&entry.url) })
+    }
+
+    /// Expires the page for the given vehicle id, its armor views, and every
+    /// armor model those armor views reference.
+    pub fn expire_vehicle(&self, vehicle_id: &str) -> std::io::Result<usize> {
+        let hashes = self.vehicle_hashes(vehicle_id);
+        self.expire_where(|entry| { hashes.contains(&entry.hash) })
+    }
+
+    /// Returns the hashes of every entry belonging to the given vehicle. Only
+    /// indexed entries are found, see `import::backfill_vehicle_index`.
+    pub fn vehicle_hashes(&self, vehicle_id: &str) -> Vec<String> {
+        let page_suffix = format!("/vehicles/{}", vehicle_id);
+
+        let entries = self.entries();
+        let pages: Vec<_> = entries.iter().filter(|entry| { entry.url.ends_with(&page_suffix) }).collect();
+        let mut models = vec!();
+        for page in pages.iter().filter(|entry| { entry.view.as_deref() == Some("armor") }) {
+            if let Ok(body) = self.read(&page.hash) {
+                models.extend(armor_models(&body).into_iter().map(|model| { format!("/armor/{}", model) }));
+            }
+        }
+
+        let mut hashes: Vec<_> = pages.iter().map(|entry| { entry.hash.clone() }).collect();
+        for entry in entries.iter() {
+            if models.iter().any(|model| { entry.url.ends_with(model) }) {
+                hashes.push(entry.hash.clone());
+            }
+        }
+        hashes
+    }
+
+    fn expire_where<F: Fn(&CacheEntry) -> bool>(&self, predicate: F) -> std::io::Result<usize> {
+        let mut index = self.index.lock().unwrap();
+        let expired: Vec<_> = index.values().filter(|entry| { predicate(entry) }).map(|entry| { entry.hash.clone() }).collect();
+        for hash in expired.iter() {
+            info!("Expiring {}", index[hash].url);
+            index.remove(hash);
+            let path = self.path(hash);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        // Compact the index
+        let tmp = self.root.join(INDEX_FILE.to_string() + ".tmp");
+        {
+            let mut f = fs::File::create(&tmp)?;
+            for entry in index.values() {
+                f.write_all((serde_json::to_string(entry)? + "\n").as_bytes())?;
+            }
+        }
+        fs::rename(tmp, self.root.join(INDEX_FILE))?;
+        Ok(expired.len())
+    }
+}
@@ -0,0 +1,201 @@
+use serde_derive::{Serialize, Deserialize};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::datasource::DataSource;
+use crate::error::{Error, Result};
+use crate::gun::{Ship, SCHEMA_VERSION};
+use crate::import::{import_vehicles, ImportReport, Progress};
+use crate::shiplist::ShipListEntry;
+use crate::util::{file_header, read_file_header, sha256, write_atomically};
+use crate::version::GameVersion;
+
+/// Database files start with this, followed by the little-endian format
+/// version, a bincode `DatabaseHeader`, and then the bincode vehicles.
+const DATABASE_MAGIC: &[u8; 8] = b"WOWSSHIP";
+const DATABASE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseHeader {
+    schema_version: u32,
+    game_version: GameVersion,
+    /// Hex SHA-256 of the vehicle data following the header
+    sha256: String,
+}
+
+/// Loads the ships database, checking that it was written for this schema
+/// and game version and hasn't been damaged since. Any mismatch is a Decode
+/// error saying what was wrong.
+pub fn load_database<P: AsRef<Path>>(path: P, version: &GameVersion) -> Result<Vec<Ship>> {
+    let path = path.as_ref();
+    let bad_database = |reason: String| {
+        Error::Decode { url: path.display().to_string(), reason: reason }
+    };
+
+    let data = fs::read(path)?;
+    let mut rest = &data[..];
+    read_file_header(&mut rest, path, "ships database", DATABASE_MAGIC, DATABASE_FORMAT_VERSION)?;
+    let header: DatabaseHeader = bincode::deserialize_from(&mut rest).map_err(|e| {
+        bad_database(e.to_string())
+    })?;
+    if header.schema_version != SCHEMA_VERSION {
+        return Err(bad_database(format!(
+            "Database has schema version {}, not {}",
+            header.schema_version, SCHEMA_VERSION
+        )));
+    }
+    if &header.game_version != version {
+        return Err(bad_database(format!(
+            "Database is for game version {}, not {}",
+            header.game_version, version
+        )));
+    }
+    if sha256(rest) != header.sha256 {
+        return Err(bad_database("Checksum mismatch".to_string()));
+    }
+
+    bincode::deserialize(rest).map_err(|e| { bad_database(e.to_string()) })
+}
+
+/// Writes the ships database. The new file is written next to the old one and
+/// then renamed over it, so an interrupted write never leaves a truncated
+/// database behind.
+pub fn save_database<P: AsRef<Path>>(path: P, version: &GameVersion, vehicles: &[Ship]) -> Result<()> {
+    let body = bincode::serialize(vehicles)?;
+    let header = DatabaseHeader {
+        schema_version: SCHEMA_VERSION,
+        game_version: version.clone(),
+        sha256: sha256(&body),
+    };
+
+    let mut data = file_header(DATABASE_MAGIC, DATABASE_FORMAT_VERSION);
+    data.append(&mut bincode::serialize(&header)?);
+    data.extend_from_slice(&body);
+
+    write_atomically(path, &data)?;
+    Ok(())
+}
+
+/// What an incremental update did to the database
+#[derive(Default)]
+pub struct DatabaseUpdate {
+    /// Vehicles which were already stored and didn't need touching
+    pub kept: usize,
+    /// Vehicles which were stored but aren't in the ship list any more
+    pub removed: Vec<String>,
+    /// Vehicles which couldn't be re-parsed, so their old copy was kept
+    pub stale: Vec<String>,
+    pub import: ImportReport,
+}
+
+impl DatabaseUpdate {
+    pub fn log(&self) {
+        self.import.log();
+        for id in self.removed.iter() {
+            info!("Removed {}, it's no longer in the ship list", id);
+        }
+        for id in self.stale.iter() {
+            warn!("Keeping the old copy of {}", id);
+        }
+        info!(
+            "Kept {} vehicles, parsed {}, removed {}",
+            self.kept,
+            self.import.imported.len(),
+            self.removed.len()
+        );
+    }
+}
+
+/// Brings `stored` up to date with the ship list. `listed` is the whole
+/// list, and stored vehicles which aren't on it are dropped. Only vehicles in
+/// `selected` (e.g. picked by `--tier`) are downloaded and parsed, if they're
+/// new or listed in `changed`; other stored vehicles are kept as they are.
+/// The result is in ship list order.
+pub fn update_database<F: FnMut(Progress)>(source: Arc<dyn DataSource>, stored: Vec<Ship>, listed: &[ShipListEntry], selected: &[ShipListEntry], changed: &[String], workers: usize, progress: F) -> (Vec<Ship>, DatabaseUpdate) {
+    let listed_ids: HashSet<_> = listed.iter().map(|entry| { entry.id.as_str() }).collect();
+    let mut update = DatabaseUpdate::default();
+
+    let mut old = vec!();
+    for vehicle in stored {
+        if listed_ids.contains(vehicle.id.as_str()) {
+            old.push(vehicle);
+        } else {
+            update.removed.push(vehicle.id.clone());
+        }
+    }
+
+    let wanted: Vec<_> = selected.iter().filter(|entry| {
+        changed.contains(&entry.id) || !old.iter().any(|vehicle| { vehicle.id == entry.id })
+    }).cloned().collect();
+    info!("{} vehicles are new or changed", wanted.len());
+    let (new, report) = import_vehicles(source, &wanted, workers, progress);
+    update.import = report;
+
+    let mut new: HashMap<_, _> = new.into_iter().map(|vehicle| { (vehicle.id.clone(), vehicle) }).collect();
+    let mut old: HashMap<_, _> = old.into_iter().map(|vehicle| { (vehicle.id.clone(), vehicle) }).collect();
+    let mut result = vec!();
+    for entry in listed {
+        match (new.remove(&entry.id), old.remove(&entry.id)) {
+            (Some(fresh), _) => { result.push(fresh); }
+            (None, Some(stored)) => {
+                if wanted.iter().any(|wanted| { wanted.id == entry.id }) {
+                    update.stale.push(entry.id.clone());
+                } else {
+                    update.kept += 1;
+                }
+                result.push(stored);
+            }
+            (None, None) => {}
+        }
+    }
+    (result, update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ship_parser::download_vehicle;
+    use crate::test_util::{fixtures, TempDir};
+
+    fn entry(id: &str, tier: Option<usize>) -> ShipListEntry {
+        ShipListEntry { id: id.to_string(), nation: "panasia".to_string(), name: None, tier: tier, class: None }
+    }
+
+    #[test]
+    fn keeps_vehicles_outside_the_selection() {
+        let source = fixtures();
+        let stored = download_vehicle(&source, "pasd014").unwrap().unwrap();
+        let mut gone = download_vehicle(&source, "pasd014").unwrap().unwrap();
+        gone.id = "pasd999".to_string();
+
+        // As with --tier 9: pasd014 is listed but not selected, and the
+        // unselected pasb006 isn't downloaded
+        let listed = vec![entry("pasd014", Some(8)), entry("pasb006", None)];
+        let (vehicles, update) = update_database(Arc::new(source), vec![stored, gone], &listed, &[], &[], 1, |_| {});
+        assert_eq!(vehicles.len(), 1);
+        assert_eq!(vehicles[0].id, "pasd014");
+        assert_eq!(update.kept, 1);
+        assert_eq!(update.removed, vec!["pasd999".to_string()]);
+        assert!(update.import.imported.is_empty());
+    }
+
+    #[test]
+    fn round_trip_keeps_armor_shared() {
+        let source = fixtures();
+        let ship = download_vehicle(&source, "pasd014").unwrap().unwrap();
+        let dir = TempDir::new("database");
+        let path = dir.join("ships.dat");
+
+        save_database(&path, &GameVersion::current(), &[ship]).unwrap();
+        let vehicles = load_database(&path, &GameVersion::current()).unwrap();
+        let ship = &vehicles[0];
+        assert_eq!(ship.armor.len(), 2);
+        assert_eq!(ship.configurations[1].geometry.len(), 3);
+        assert!(Arc::ptr_eq(&ship.configurations[0].geometry, &ship.configurations[1].geometry));
+        assert_eq!(ship.configurations[4].geometry.len(), 4);
+        assert!(Arc::ptr_eq(&ship.configurations[4].geometry, &ship.configurations[7].geometry));
+    }
+}
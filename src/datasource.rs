@@ -0,0 +1,171 @@
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cache::{Cache, url_hash, params_hash};
+use crate::download::{download, download_with_params};
+use crate::error::{Error, Result};
+use crate::version::GameVersion;
+
+/// Somewhere to get vehicle pages, ship lists and armor models from.
+pub trait DataSource: Send + Sync {
+    fn fetch(&self, url: &str) -> Result<String>;
+
+    /// Fetches a view of a page, such as the armor view of a vehicle
+    fn fetch_with_params(&self, url: &str, view: &str, params: &str) -> Result<String>;
+
+    /// The game version whose data this source serves
+    fn version(&self) -> &GameVersion;
+}
+
+fn read_cached(cache: &Cache, url: &str, hash: String) -> Result<String> {
+    if !cache.contains(&hash) {
+        return Err(Error::NotCached { url: url.to_string(), hash: hash });
+    }
+    Ok(cache.read(&hash)?)
+}
+
+/// Downloads from the network, going through the cache.
+///
+/// Only the data files (armor models) are kept per version on the site; the
+/// vehicle pages are always the live ones. Unless `live` says the site is
+/// serving `version`, pages are only read from the cache, so that a newer
+/// patch's pages don't end up filed under an older version.
+#[derive(new)]
+pub struct HttpSource {
+    cache: Arc<Cache>,
+    version: GameVersion,
+    live: bool,
+}
+
+impl HttpSource {
+    fn is_pinned(&self, url: &str) -> bool {
+        self.live || url.starts_with(&self.version.data_url())
+    }
+}
+
+impl DataSource for HttpSource {
+    fn fetch(&self, url: &str) -> Result<String> {
+        if !self.is_pinned(url) {
+            return read_cached(&self.cache, url, url_hash(url));
+        }
+        download(&self.cache, url)
+    }
+
+    fn fetch_with_params(&self, url: &str, view: &str, params: &str) -> Result<String> {
+        if !self.is_pinned(url) {
+            let description = format!("{} (view={}, params={})", url, view, params);
+            return read_cached(&self.cache, &description, params_hash(url, view, params));
+        }
+        download_with_params(&self.cache, url, view, params)
+    }
+
+    fn version(&self) -> &GameVersion {
+        &self.version
+    }
+}
+
+/// Serves only what's already in the cache, never touching the network
+#[derive(new)]
+pub struct CacheSource {
+    cache: Arc<Cache>,
+    version: GameVersion,
+}
+
+impl DataSource for CacheSource {
+    fn fetch(&self, url: &str) -> Result<String> {
+        read_cached(&self.cache, url, url_hash(url))
+    }
+
+    fn fetch_with_params(&self, url: &str, view: &str, params: &str) -> Result<String> {
+        let description = format!("{} (view={}, params={})", url, view, params);
+        read_cached(&self.cache, &description, params_hash(url, view, params))
+    }
+
+    fn version(&self) -> &GameVersion {
+        &self.version
+    }
+}
+
+#[derive(Deserialize)]
+struct FixtureManifestEntry {
+    url: String,
+    view: Option<String>,
+    params: Option<String>,
+    file: String,
+}
+
+/// Serves fixed pages from memory, for running the parser against known
+/// inputs.
+///
+/// Fixtures can be loaded from a directory containing a `manifest.json`,
+/// which lists the pages and which file holds each one:
+/// ```json
+/// [
+///   {"url": "https://gamemodels3d.com/games/worldofwarships/vehicles/pasd014", "file": "pasd014.html"},
+///   {"url": "https://gamemodels3d.com/games/worldofwarships/vehicles/pasd014", "view": "armor", "params": "{...}", "file": "pasd014_armor.html"}
+/// ]
+/// ```
+pub struct FixtureSource {
+    pages: HashMap<String, String>,
+    version: GameVersion,
+}
+
+impl FixtureSource {
+    pub fn new(version: GameVersion) -> FixtureSource {
+        FixtureSource {
+            pages: HashMap::new(),
+            version: version,
+        }
+    }
+
+    pub fn from_dir<P: AsRef<Path>>(dir: P, version: GameVersion) -> Result<FixtureSource> {
+        let dir = dir.as_ref();
+        let manifest_path = dir.join("manifest.json");
+        let manifest: Vec<FixtureManifestEntry> = serde_json::from_str(&fs::read_to_string(&manifest_path)?).map_err(|e| {
+            Error::Decode { url: manifest_path.display().to_string(), reason: e.to_string() }
+        })?;
+
+        let mut fixtures = FixtureSource::new(version);
+        for entry in manifest {
+            let body = fs::read_to_string(dir.join(&entry.file))?;
+            match (entry.view, entry.params) {
+                (Some(view), Some(params)) => { fixtures.insert_with_params(&entry.url, &view, &params, body); }
+                _ => { fixtures.insert(&entry.url, body); }
+            }
+        }
+        Ok(fixtures)
+    }
+
+    pub fn insert(&mut self, url: &str, body: String) {
+        self.pages.insert(url_hash(url), body);
+    }
+
+    pub fn insert_with_params(&mut self, url: &str, view: &str, params: &str, body: String) {
+        self.pages.insert(params_hash(url, view, params), body);
+    }
+
+    fn get(&self, url: &str, hash: String) -> Result<String> {
+        match self.pages.get(&hash) {
+            Some(body) => Ok(body.clone()),
+            None => Err(Error::NotCached { url: url.to_string(), hash: hash }),
+        }
+    }
+}
+
+impl DataSource for FixtureSource {
+    fn fetch(&self, url: &str) -> Result<String> {
+        self.get(url, url_hash(url))
+    }
+
+    fn fetch_with_params(&self, url: &str, view: &str, params: &str) -> Result<String> {
+        let description = format!("{} (view={}, params={})", url, view, params);
+        self.get(&description, params_hash(url, view, params))
+    }
+
+    fn version(&self) -> &GameVersion {
+        &self.version
+    }
+}
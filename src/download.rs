@@ -1,72 +1,508 @@
-use sha2::{Sha256, Digest};
-use std::path::Path;
-use std::fs;
 use flate2::read::GzDecoder;
 use std::io::prelude::*;
 use log::{warn, info};
 //use url::form_urlencoded;
 //use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 
-pub fn download(url: &str) -> String {
-    let result = Sha256::digest(url.as_bytes());
+use crate::cache::{Cache, url_hash, params_hash, CacheEntry};
+use crate::error::{Error, Result};
 
-    let path = Path::new("cache/").join(hex::encode(&result[..]));
-    if path.exists() {
-        return fs::read_to_string(path).unwrap();
+/// Environment variable which, when set to anything other than "" or "0",
+/// forces offline mode.
+pub const OFFLINE_ENV_VAR: &str = "WOWS_ARMOR_OFFLINE";
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Total bytes received from the network by this process
+static BYTES_FETCHED: AtomicU64 = AtomicU64::new(0);
+
+/// Enables or disables offline mode. In offline mode, only the contents of
+/// `cache/` are served and a cache miss is an error.
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::SeqCst);
+}
+
+pub fn bytes_fetched() -> u64 {
+    BYTES_FETCHED.load(Ordering::SeqCst)
+}
+
+pub fn is_offline() -> bool {
+    if OFFLINE.load(Ordering::SeqCst) {
+        return true;
+    }
+    match std::env::var(OFFLINE_ENV_VAR) {
+        Ok(v) => { v != "" && v != "0" }
+        Err(_) => { false }
     }
+}
 
-    let response = reqwest::blocking::get(url).unwrap();
-    let body = if response.status() == 404 {
-        // Sometimes some armor models return 404, we can't panic when that happens
-        warn!("Got response code {} for url {}", response.status(), url);
-        "".to_string()
-    } else {
-        let body = response.bytes().unwrap();
-
-        info!("Downloaded {}: {} bytes", url, body.len());
-        if url.ends_with(".gz") {
-            // Decompress
-            let mut d = GzDecoder::new(&body[..]);
-            let mut s = String::new();
-            d.read_to_string(&mut s).unwrap();
-            s
-        } else {
-            std::str::from_utf8(&body).unwrap().to_string()
+/// Environment variables which override the defaults in `DownloadConfig`
+pub const RETRIES_ENV_VAR: &str = "WOWS_ARMOR_RETRIES";
+pub const RATE_LIMIT_ENV_VAR: &str = "WOWS_ARMOR_RATE_LIMIT";
+pub const TIMEOUT_ENV_VAR: &str = "WOWS_ARMOR_TIMEOUT";
+pub const USER_AGENT_ENV_VAR: &str = "WOWS_ARMOR_USER_AGENT";
+
+/// How we talk to the network
+#[derive(Clone, Debug)]
+pub struct DownloadConfig {
+    /// How many times to retry a request which timed out or got a 5xx
+    pub retries: u32,
+    /// The wait before the first retry, doubling for every retry after that
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub timeout: Duration,
+    /// Maximum requests per second to any single host
+    pub rate_limit: f64,
+    pub user_agent: String,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> DownloadConfig {
+        DownloadConfig {
+            retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(60),
+            rate_limit: 4.0,
+            user_agent: format!("wows_armor/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// The default config, with any overrides from the environment
+    pub fn from_env() -> DownloadConfig {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            let value = std::env::var(name).ok()?;
+            match value.parse() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    warn!("Ignoring bad value {:?} for {}", value, name);
+                    None
+                }
+            }
+        }
+
+        let mut config = DownloadConfig::default();
+        if let Some(retries) = var(RETRIES_ENV_VAR) {
+            config.retries = retries;
+        }
+        if let Some(rate_limit) = var(RATE_LIMIT_ENV_VAR) {
+            config.rate_limit = rate_limit;
+        }
+        if let Some(timeout) = var(TIMEOUT_ENV_VAR) {
+            config.timeout = Duration::from_secs(timeout);
+        }
+        if let Some(user_agent) = var(USER_AGENT_ENV_VAR) {
+            config.user_agent = user_agent;
         }
+        config
+    }
+
+    fn build_client(&self) -> Client {
+        Client::builder()
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent)
+            .build()
+            .expect("Couldn't build HTTP client")
+    }
+
+    /// The wait before the given retry (starting at 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff * 2u32.saturating_pow(retry.saturating_sub(1));
+        if backoff > self.max_backoff { self.max_backoff } else { backoff }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<(DownloadConfig, Client)> = {
+        let config = DownloadConfig::from_env();
+        let client = config.build_client();
+        RwLock::new((config, client))
     };
-    fs::write(path, body.clone()).unwrap();
-    body
+
+    /// When each host may next be sent a request
+    static ref NEXT_REQUEST: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
 }
 
+/// Replaces the network config used by `download`, `download_with_params`
+/// and `fetch_uncached`
+pub fn configure(config: DownloadConfig) {
+    let client = config.build_client();
+    *CONFIG.write().unwrap() = (config, client);
+}
 
-pub fn download_with_params(url: &str, view: &str, params: &str) -> String {
-    let to_hash = url.to_string() + view + params;
-    let result = Sha256::digest(to_hash.as_bytes());
+/// Blocks until we're allowed to send another request to the URL's host
+fn wait_for_host(url: &str, rate_limit: f64) {
+    if rate_limit <= 0.0 {
+        return;
+    }
+    let host = match reqwest::Url::parse(url) {
+        Ok(url) => url.host_str().unwrap_or("").to_string(),
+        Err(_) => "".to_string(),
+    };
 
-    let path = Path::new("cache/").join(hex::encode(&result[..]));
-    if path.exists() {
-        return fs::read_to_string(path).unwrap();
+    // Reserve the next slot for this host, so that concurrent callers queue
+    // up behind each other instead of all waking at once
+    let now = Instant::now();
+    let slot = {
+        let mut next_request = NEXT_REQUEST.lock().unwrap();
+        let next = next_request.entry(host).or_insert(now);
+        let slot = if *next > now { *next } else { now };
+        *next = slot + Duration::from_secs_f64(1.0 / rate_limit);
+        slot
+    };
+    if slot > now {
+        std::thread::sleep(slot - now);
     }
+}
 
-    let client = reqwest::blocking::Client::new();
+/// A response, with its body read
+struct Sent {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
 
-    let mut raw_params = HashMap::new();
-    raw_params.insert("view", view);
-    raw_params.insert("params", params);
+/// Sends the request built by `build` and reads the response, retrying with
+/// exponential backoff if it times out, can't connect, gets cut off partway
+/// through the body, or gets a 5xx response.
+fn send<F: Fn(&Client) -> RequestBuilder>(url: &str, build: F) -> Result<Sent> {
+    let (config, client) = CONFIG.read().unwrap().clone();
+    send_with(&config, &client, url, build)
+}
 
-    let response = client.post(url).form(&raw_params).send().unwrap();
-    let body = response.bytes().unwrap();
-    info!("Downloaded {} with params: {} bytes", url, body.len());
-    let body = if url.ends_with(".gz") {
+fn send_with<F: Fn(&Client) -> RequestBuilder>(config: &DownloadConfig, client: &Client, url: &str, build: F) -> Result<Sent> {
+    let mut retry = 0;
+    loop {
+        wait_for_host(url, config.rate_limit);
+        // `send` returns as soon as the headers arrive, so the body has to be
+        // read here too for failures reading it to be retried
+        let sent = build(client).send().and_then(|response| {
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.bytes()?;
+            BYTES_FETCHED.fetch_add(body.len() as u64, Ordering::SeqCst);
+            Ok(Sent { status: status, headers: headers, body: body.to_vec() })
+        });
+        let (retryable, result) = match sent {
+            Ok(sent) => {
+                (sent.status.is_server_error(), Ok(sent))
+            }
+            Err(e) => {
+                (e.is_timeout() || e.is_connect() || e.is_body(), Err(network_error(url, e)))
+            }
+        };
+        if !retryable || retry >= config.retries {
+            return result;
+        }
+        retry += 1;
+        let backoff = config.backoff(retry);
+        match result {
+            Ok(sent) => { warn!("Got response code {} for url {}, retrying in {:?}", sent.status, url, backoff); }
+            Err(e) => { warn!("{}, retrying in {:?}", e, backoff); }
+        }
+        std::thread::sleep(backoff);
+    }
+}
+
+/// Decompresses a response body if the URL points at a `.gz` file
+fn decompress_body(url: &str, body: &[u8]) -> Result<Vec<u8>> {
+    if url.ends_with(".gz") {
         // Decompress
-        let mut d = GzDecoder::new(&body[..]);
-        let mut s = String::new();
-        d.read_to_string(&mut s).unwrap();
-        s
+        let mut d = GzDecoder::new(body);
+        let mut s = vec!();
+        d.read_to_end(&mut s).map_err(|e| {
+            Error::Decode { url: url.to_string(), reason: e.to_string() }
+        })?;
+        Ok(s)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+fn to_text(url: &str, body: Vec<u8>) -> Result<String> {
+    String::from_utf8(body).map_err(|e| {
+        Error::Decode { url: url.to_string(), reason: e.to_string() }
+    })
+}
+
+fn network_error(url: &str, e: reqwest::Error) -> Error {
+    Error::Network { url: url.to_string(), source: e }
+}
+
+/// Fetches the URL without going through the cache, for things which must
+/// always be fresh.
+pub fn fetch_uncached(url: &str) -> Result<String> {
+    if is_offline() {
+        return Err(Error::NotCached { url: url.to_string(), hash: url_hash(url) });
+    }
+    let sent = send(url, |client| { client.get(url) })?;
+    if !sent.status.is_success() {
+        return Err(Error::HttpStatus { url: url.to_string(), status: sent.status.as_u16() });
+    }
+    to_text(url, decompress_body(url, &sent.body)?)
+}
+
+/// Downloads the URL as text, going through the cache
+pub fn download(cache: &Cache, url: &str) -> Result<String> {
+    to_text(url, download_bytes(cache, url)?)
+}
+
+pub fn download_bytes(cache: &Cache, url: &str) -> Result<Vec<u8>> {
+    download_entry(cache, url, None)
+}
+
+
+/// Downloads a view of the URL as text, going through the cache
+pub fn download_with_params(cache: &Cache, url: &str, view: &str, params: &str) -> Result<String> {
+    to_text(url, download_bytes_with_params(cache, url, view, params)?)
+}
+
+pub fn download_bytes_with_params(cache: &Cache, url: &str, view: &str, params: &str) -> Result<Vec<u8>> {
+    download_entry(cache, url, Some((view, params)))
+}
+
+fn download_entry(cache: &Cache, url: &str, view_params: Option<(&str, &str)>) -> Result<Vec<u8>> {
+    let hash = match view_params {
+        Some((view, params)) => params_hash(url, view, params),
+        None => url_hash(url),
+    };
+    if cache.contains(&hash) {
+        return Ok(cache.read_bytes(&hash)?);
+    }
+    if is_offline() {
+        let url = match view_params {
+            Some((view, params)) => format!("{} (view={}, params={})", url, view, params),
+            None => url.to_string(),
+        };
+        return Err(Error::NotCached { url: url, hash: hash });
+    }
+
+    match fetch(url, view_params, None)? {
+        Fetched::Fetched(entry, body) => {
+            cache.insert(entry, &body)?;
+            Ok(body)
+        }
+        Fetched::NotModified => {
+            unreachable!("Got 304 Not Modified for an unconditional request");
+        }
+    }
+}
+
+/// Checks a cached entry against the server, using its ETag and
+/// Last-Modified headers if it has them, and refreshes it if the server has
+/// something new. Returns whether the contents changed.
+pub fn revalidate(cache: &Cache, entry: &CacheEntry) -> Result<bool> {
+    let view_params = match (&entry.view, &entry.params) {
+        (Some(view), Some(params)) => Some((view.as_str(), params.as_str())),
+        _ => None,
+    };
+    match fetch(&entry.url, view_params, Some(entry))? {
+        Fetched::NotModified => {
+            cache.touch(&entry.hash)?;
+            Ok(false)
+        }
+        Fetched::Fetched(new_entry, body) => {
+            let changed = match cache.read_bytes(&entry.hash) {
+                Ok(old_body) => old_body != body,
+                Err(_) => true,
+            };
+            if changed {
+                info!("{} has changed", entry.url);
+            }
+            cache.insert(new_entry, &body)?;
+            Ok(changed)
+        }
+    }
+}
+
+enum Fetched {
+    NotModified,
+    Fetched(CacheEntry, Vec<u8>),
+}
+
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers.get(name).and_then(|v| { v.to_str().ok() }).map(|v| { v.to_string() })
+}
+
+/// Fetches a URL (or a view of it) from the network. If `previous` is given,
+/// the request is made conditional on its ETag and Last-Modified.
+fn fetch(url: &str, view_params: Option<(&str, &str)>, previous: Option<&CacheEntry>) -> Result<Fetched> {
+    let mut raw_params = HashMap::new();
+    if let Some((view, params)) = view_params {
+        raw_params.insert("view", view);
+        raw_params.insert("params", params);
+    }
+
+    let sent = send(url, |client| {
+        let mut request = match view_params {
+            Some(_) => client.post(url).form(&raw_params),
+            None => client.get(url),
+        };
+        if let Some(previous) = previous {
+            if let Some(etag) = &previous.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &previous.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+        request
+    })?;
+    let status = sent.status;
+    if status == StatusCode::NOT_MODIFIED && previous.is_some() {
+        return Ok(Fetched::NotModified);
+    }
+
+    let content_type = header(&sent.headers, reqwest::header::CONTENT_TYPE);
+    let etag = header(&sent.headers, reqwest::header::ETAG);
+    let last_modified = header(&sent.headers, reqwest::header::LAST_MODIFIED);
+    let body = if status == 404 && view_params.is_none() {
+        // Sometimes some armor models return 404, we can't panic when that happens
+        warn!("Got response code {} for url {}", status, url);
+        vec!()
+    } else if !status.is_success() {
+        return Err(Error::HttpStatus { url: url.to_string(), status: status.as_u16() });
     } else {
-        std::str::from_utf8(&body).unwrap().to_string()
+        match view_params {
+            Some(_) => { info!("Downloaded {} with params: {} bytes", url, sent.body.len()); }
+            None => { info!("Downloaded {}: {} bytes", url, sent.body.len()); }
+        }
+        decompress_body(url, &sent.body)?
     };
-    fs::write(path, body.clone()).unwrap();
-    body
+
+    let mut entry = CacheEntry::new(url, view_params.map(|(view, _)| { view }), view_params.map(|(_, params)| { params }), status.as_u16(), body.len());
+    entry.content_type = content_type;
+    entry.etag = etag;
+    entry.last_modified = last_modified;
+    Ok(Fetched::Fetched(entry, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    enum Reply {
+        /// The status, with a body of "ok"
+        Status(u16),
+        /// A 200 whose body stops halfway, until the client gives up on it
+        StallMidBody,
+    }
+
+    /// A stand-in HTTP server which answers one request with each of
+    /// `replies` and then stops. Returns its URL and the (lowercased)
+    /// requests it got.
+    fn serve_replies(replies: Vec<Reply>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/vehicles/usa", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec!()));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![0; 4096];
+                let n = stream.read(&mut request).unwrap();
+                received.lock().unwrap().push(String::from_utf8_lossy(&request[..n]).to_lowercase());
+                match reply {
+                    Reply::Status(status) => {
+                        write!(stream, "HTTP/1.1 {} Test\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status).unwrap();
+                    }
+                    Reply::StallMidBody => {
+                        write!(stream, "HTTP/1.1 200 Test\r\nContent-Length: 2\r\nConnection: close\r\n\r\no").unwrap();
+                        stream.flush().unwrap();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(2000));
+                            drop(stream);
+                        });
+                    }
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    fn serve(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        serve_replies(statuses.into_iter().map(Reply::Status).collect())
+    }
+
+    fn test_config(retries: u32) -> DownloadConfig {
+        DownloadConfig {
+            retries: retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            timeout: Duration::from_secs(10),
+            rate_limit: 0.0,
+            user_agent: "wows_armor_test/1.0".to_string(),
+        }
+    }
+
+    fn get(config: &DownloadConfig, url: &str) -> Result<Sent> {
+        send_with(config, &config.build_client(), url, |client| { client.get(url) })
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, requests) = serve(vec![503, 500, 200]);
+        let sent = get(&test_config(3), &url).unwrap();
+        assert_eq!(sent.status, 200);
+        assert_eq!(sent.body, b"ok");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retries_bodies_which_stall() {
+        let (url, requests) = serve_replies(vec![Reply::StallMidBody, Reply::Status(200)]);
+        let config = DownloadConfig {
+            timeout: Duration::from_millis(300),
+            ..test_config(3)
+        };
+        let sent = get(&config, &url).unwrap();
+        assert_eq!(sent.body, b"ok");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let (url, requests) = serve(vec![500, 500, 500, 200]);
+        let sent = get(&test_config(2), &url).unwrap();
+        assert_eq!(sent.status, 500);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, requests) = serve(vec![404, 200]);
+        let sent = get(&test_config(3), &url).unwrap();
+        assert_eq!(sent.status, 404);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sends_user_agent() {
+        let (url, requests) = serve(vec![200]);
+        get(&test_config(0), &url).unwrap();
+        assert!(requests.lock().unwrap()[0].contains("user-agent: wows_armor_test/1.0\r\n"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let config = DownloadConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..test_config(5)
+        };
+        assert_eq!(config.backoff(1), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(200));
+        assert_eq!(config.backoff(3), Duration::from_millis(350));
+    }
 }
@@ -0,0 +1,74 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request itself failed (connection refused, timeout, etc.)
+    Network { url: String, source: reqwest::Error },
+    /// The server responded, but not with something we can use
+    HttpStatus { url: String, status: u16 },
+    /// The response couldn't be decoded (bad gzip, bad UTF-8, bad JSON, or
+    /// the page didn't look like we expected)
+    Decode { url: String, reason: String },
+    /// A field was missing or had the wrong type. The path is the JSON path
+    /// to the field, e.g. `Components.AB1_Artillery.maxDist`
+    MissingField { path: String },
+    /// A field had a value we don't know how to handle
+    UnknownValue { path: String, value: String },
+    /// We're in offline mode and the requested URL isn't in the cache
+    NotCached { url: String, hash: String },
+    /// The worker thread handling this vehicle panicked
+    WorkerPanicked { vehicle_id: String },
+    Io(std::io::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network { url, source } => { write!(f, "Couldn't fetch {}: {}", url, source) }
+            Error::HttpStatus { url, status } => { write!(f, "Got HTTP status {} for {}", status, url) }
+            Error::Decode { url, reason } => { write!(f, "Couldn't decode {}: {}", url, reason) }
+            Error::MissingField { path } => { write!(f, "Missing or invalid field {}", path) }
+            Error::UnknownValue { path, value } => { write!(f, "Unknown value {:?} for {}", value, path) }
+            Error::NotCached { url, hash } => { write!(f, "{} is not cached ({})", url, hash) }
+            Error::WorkerPanicked { vehicle_id } => { write!(f, "The worker handling {} panicked", vehicle_id) }
+            Error::Io(e) => { write!(f, "I/O error: {}", e) }
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => { write!(f, "SQLite error: {}", e) }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network { source, .. } => Some(source),
+            Error::Io(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
@@ -5,8 +5,15 @@ use cgmath::{Vector3, Point3};
 use cgmath::prelude::*;
 use log::{debug, trace};
 use rand::Rng;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-
+/// Version of the structs stored in `ships.dat`. Bump this whenever any of
+/// them change shape, so old databases get rebuilt instead of mis-decoded,
+/// or when the parser fills them in differently, so old databases get rebuilt
+/// with the new data. The JSON export has its own structs in `json_export`,
+/// which need updating separately for a change to show up there.
+pub const SCHEMA_VERSION: u32 = 14;
 
 fn deg2rad(x: f64) -> f64 {
     x * 3.14159265 / 180.0
@@ -90,8 +97,8 @@ pub trait Bullet {
 
 #[derive(new, Serialize, Deserialize)]
 pub struct HeAmmo {
-    damage: f64,
-    piercing: f64,
+    pub damage: f64,
+    pub piercing: f64,
 }
 
 impl Bullet for HeAmmo {
@@ -118,12 +125,101 @@ impl Bullet for HeAmmo {
     }
 }
 
+/// Angles of incidence (from the armor's normal, in degrees) beyond which a
+/// shell may ricochet, and beyond which it always does
+#[derive(new, Clone, Debug, Serialize, Deserialize)]
+pub struct RicochetAngles {
+    pub ricochet_at: f64,
+    pub always_ricochet_at: f64,
+}
+
+impl RicochetAngles {
+    /// `surface_angle` is the angle between the shell's path and the armor
+    /// face, as in `Intersection::angle`. Between the two angles the chance
+    /// of a ricochet goes up linearly.
+    pub fn ricochets(&self, surface_angle: f64) -> bool {
+        let incidence = 90.0 - surface_angle;
+        if incidence >= self.always_ricochet_at {
+            true
+        } else if incidence > self.ricochet_at {
+            let probability = (incidence - self.ricochet_at) / (self.always_ricochet_at - self.ricochet_at);
+            let mut rng = rand::thread_rng();
+            rng.gen::<f64>() < probability
+        } else {
+            false
+        }
+    }
+}
+
+/// Whether a shell `diameter` meters across overmatches `thickness` mm of
+/// armor: shells more than 14.3 times the armor's thickness can't ricochet
+/// off it
+fn overmatches(diameter: f64, thickness: f64) -> bool {
+    thickness * 14.3 < diameter * 1000.0
+}
+
+/// Semi-armor-piercing shells. They penetrate a fixed thickness like HE, but
+/// can ricochet like AP, and have no fuse: they go off on the first plate
+/// they get through.
+#[derive(new, Serialize, Deserialize)]
+pub struct SapAmmo {
+    pub diameter: f64, // m
+    pub damage: f64,
+    pub piercing: f64, // mm
+    pub ricochet: RicochetAngles,
+}
+
+impl Bullet for SapAmmo {
+    fn compute_damage(&self, target: &ShipConfiguration, _penetration: f64, _speed: f64, direction: Vector3<f64>, offset: Point3<f64>) -> (f64, ImpactType) {
+        debug!("Computing damage for SAP ammo");
+        let (mut path, mut armorface, mut intersection) = match ImpactPath::new(target, direction, offset) {
+            Some(x) => { x }
+            None => {
+                debug!("Trajectory was a miss!");
+                return (0.0, ImpactType::Miss);
+            }
+        };
+
+        loop {
+            trace!("Impact: {:?}, {}mm at {} degrees", intersection.intersect_point, armorface.thickness, intersection.angle);
+            if !overmatches(self.diameter, armorface.thickness) && self.ricochet.ricochets(intersection.angle) {
+                let x = match path.ricochet() {
+                    Some(x) => x,
+                    None => {
+                        return (0.0, ImpactType::Ricochet);
+                    }
+                };
+                armorface = x.0;
+                intersection = x.1;
+                continue;
+            }
+
+            if armorface.thickness > self.piercing {
+                debug!("Non-penetration");
+                return (0.0, ImpactType::NonPenetration);
+            }
+            return match armorface.armor_type {
+                ArmorType::Citadel => {
+                    debug!("Citadel hit!");
+                    (self.damage, ImpactType::Citadel)
+                }
+                ArmorType::TorpedoProtectionBelt => (0.0, ImpactType::TorpedoProtection),
+                _ => (self.damage / 3.0, ImpactType::Penetration),
+            };
+        }
+    }
+}
+
 #[derive(new, Serialize, Deserialize)]
 pub struct ApAmmo {
-    diameter: f64,
-    damage: f64,
-    detonator: f64,
-    detonator_threshold: f64,
+    pub diameter: f64,
+    pub damage: f64,
+    pub detonator: f64,
+    pub detonator_threshold: f64,
+    pub ricochet: RicochetAngles,
+    /// How far (in degrees) the shell turns towards the armor's normal when it
+    /// hits
+    pub normalization: f64,
 }
 
 impl Bullet for ApAmmo {
@@ -170,19 +266,7 @@ impl Bullet for ApAmmo {
                 }
             }
 
-            let ricochet = if armorface.thickness < self.diameter / 14.3 {
-                false
-            } else if intersection.angle < 30.0 {
-                true
-            } else if intersection.angle < 45.0 {
-                let probability = (intersection.angle - 30.0) / 15.0;
-                let mut rng = rand::thread_rng();
-                rng.gen::<f64>() < probability
-            } else {
-                false
-            };
-
-            if ricochet {
+            if !overmatches(self.diameter, armorface.thickness) && self.ricochet.ricochets(intersection.angle) {
                 let x = match path.ricochet() {
                     Some(x) => x,
                     None => {
@@ -193,7 +277,7 @@ impl Bullet for ApAmmo {
                 intersection = x.1;
             } else {
                 // Thickness normalization
-                let angle = if 0.0 > intersection.angle - 6.0 { 0.0 } else { intersection.angle - 6.0 };
+                let angle = if 0.0 > intersection.angle - self.normalization { 0.0 } else { intersection.angle - self.normalization };
                 let normalized_thickness = armorface.thickness / deg2rad(90.0 - angle).cos();
 
                 penetration -= normalized_thickness;
@@ -226,6 +310,7 @@ impl Bullet for ApAmmo {
 pub enum AmmoType {
     He(HeAmmo),
     Ap(ApAmmo),
+    Sap(SapAmmo),
 }
 
 impl Bullet for AmmoType {
@@ -233,6 +318,7 @@ impl Bullet for AmmoType {
         match self {
             AmmoType::He(he) => { he.compute_damage(target, penetration, speed, direction, offset) }
             AmmoType::Ap(ap) => { ap.compute_damage(target, penetration, speed, direction, offset) }
+            AmmoType::Sap(sap) => { sap.compute_damage(target, penetration, speed, direction, offset) }
         }
     }
 }
@@ -247,27 +333,91 @@ pub struct Ammo {
 pub struct Gun {
     pub dispersion: Dispersion,
     pub ammo: Vec<Ammo>,
+    pub range: f64, // m
+    pub reload: f64, // s
+    /// Barrels in the turret, each firing a shell per salvo
+    pub barrels: usize,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub enum ArmorType {
-    Normal,
-    Citadel,
-    TorpedoProtectionBelt,
+#[derive(new, Serialize, Deserialize)]
+pub struct TorpedoAmmo {
+    pub damage: f64,
+    pub speed: f64, // m/s
+    pub range: f64, // m
+    /// Chance of causing a flood, from 0 to 1
+    pub flooding: f64,
 }
 
-impl ArmorType {
-    pub fn from_id(id: usize) -> ArmorType {
-        if id >= 59 && id <= 67 {
-            ArmorType::Citadel
-        } else if id == 101 {
-            ArmorType::TorpedoProtectionBelt
+impl Bullet for TorpedoAmmo {
+    /// Torpedoes run at the waterline, so only the horizontal part of
+    /// `direction` matters and `offset` should be at the waterline. The
+    /// torpedo protection belt counts if it's met before the citadel, i.e. on
+    /// the side that was hit, and takes off the target's `torpedo_protection`.
+    fn compute_damage(&self, target: &ShipConfiguration, _penetration: f64, _speed: f64, direction: Vector3<f64>, offset: Point3<f64>) -> (f64, ImpactType) {
+        debug!("Computing damage for torpedo");
+        let direction = Vector3::new(direction.x, 0.0, direction.z).normalize();
+        let (mut path, mut armorface, _) = match ImpactPath::new(target, direction, offset) {
+            Some(x) => { x }
+            None => {
+                debug!("Torpedo missed!");
+                return (0.0, ImpactType::Miss);
+            }
+        };
+        loop {
+            match armorface.armor_type {
+                ArmorType::TorpedoProtectionBelt => {
+                    debug!("Torpedo hit the torpedo protection");
+                    return (self.damage * (1.0 - target.torpedo_protection), ImpactType::TorpedoProtection);
+                }
+                ArmorType::Citadel => { break; }
+                _ => {}
+            }
+            armorface = match path.penetrate() {
+                Some((face, _)) => { face }
+                None => { break; }
+            };
+        }
+        (self.damage, ImpactType::Penetration)
+    }
+}
+
+#[derive(new, Serialize, Deserialize)]
+pub struct TorpedoLauncher {
+    pub ammo: Vec<TorpedoAmmo>,
+    pub barrels: usize,
+    pub reload: f64, // s
+    /// The bearings the launcher can fire at, in degrees clockwise from the
+    /// bow, as [from, to]
+    pub arc: [f64; 2],
+}
+
+impl TorpedoLauncher {
+    /// Whether the launcher can fire at `bearing` degrees from the bow
+    pub fn can_fire_at(&self, bearing: f64) -> bool {
+        // A whole circle would normalize to a single bearing
+        if self.arc[1] - self.arc[0] >= 360.0 {
+            return true;
+        }
+        let normalize = |angle: f64| { (angle + 180.0).rem_euclid(360.0) - 180.0 };
+        let (from, to, bearing) = (normalize(self.arc[0]), normalize(self.arc[1]), normalize(bearing));
+        if from <= to {
+            from <= bearing && bearing <= to
         } else {
-            ArmorType::Normal
+            // The arc wraps around the stern
+            bearing >= from || bearing <= to
         }
     }
 }
 
+/// Where a piece of armor is, as far as the simulation cares. Which material
+/// ids are which comes from the table in `materials`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ArmorType {
+    Normal,
+    Citadel,
+    TorpedoProtectionBelt,
+}
+
 #[derive(new, Clone, Serialize, Deserialize)]
 pub struct ArmorFace {
     pub vertices: [Point3<f64>; 3],
@@ -345,16 +495,160 @@ impl ArmorFace {
     }
 }
 
+/// Once a section has taken its `max_hp`, further damage to it is scaled by
+/// this much
+const SATURATED_DAMAGE_FACTOR: f64 = 0.5;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HitLocationType {
+    Bow,
+    Stern,
+    Casemate,
+    Superstructure,
+    Citadel,
+    Turret,
+    /// A section we don't know about, with the game's `hlType`
+    Other(String),
+}
+
+impl HitLocationType {
+    /// Maps the game's `hlType` names
+    pub fn from_name(name: &str) -> HitLocationType {
+        match name {
+            "Bow" => HitLocationType::Bow,
+            "St" | "Stern" => HitLocationType::Stern,
+            "Cas" | "Casemate" => HitLocationType::Casemate,
+            "SS" | "SSC" | "Superstructure" => HitLocationType::Superstructure,
+            "Cit" | "Citadel" => HitLocationType::Citadel,
+            "Art" | "Tur" | "Turret" => HitLocationType::Turret,
+            _ => HitLocationType::Other(name.to_string()),
+        }
+    }
+}
+
+/// One section of a ship's hit points, e.g. the bow
+#[derive(new, Clone, Debug, Serialize, Deserialize)]
+pub struct HitLocation {
+    /// The section's key in the game data, e.g. "Bow" or "HP_AGM_1"
+    pub name: String,
+    pub kind: HitLocationType,
+    /// How much damage the section takes before it saturates
+    pub max_hp: f64,
+}
+
+impl HitLocation {
+    /// How much of `damage` counts against the ship when the section has
+    /// already taken `taken`: in full up to its `max_hp`, reduced after.
+    pub fn saturated_damage(&self, taken: f64, damage: f64) -> f64 {
+        let full = damage.min((self.max_hp - taken).max(0.0));
+        full + (damage - full) * SATURATED_DAMAGE_FACTOR
+    }
+}
+
+#[derive(new, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HitPoints {
+    pub health: f64,
+    pub sections: Vec<HitLocation>,
+}
+
+impl HitPoints {
+    /// The fraction of the ship's health a section holds
+    pub fn share(&self, section: &HitLocation) -> f64 {
+        if self.health > 0.0 {
+            section.max_hp / self.health
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Keeps track of the damage each section of a ship has taken, so that
+/// repeated hits saturate
+pub struct DamageTracker<'a> {
+    hit_points: &'a HitPoints,
+    taken: Vec<f64>,
+    total: f64,
+}
+
+impl<'a> DamageTracker<'a> {
+    pub fn new(hit_points: &'a HitPoints) -> DamageTracker<'a> {
+        DamageTracker {
+            hit_points: hit_points,
+            taken: vec![0.0; hit_points.sections.len()],
+            total: 0.0,
+        }
+    }
+
+    /// Applies a hit on the first section of the given type, returning the
+    /// damage that counted. Ships without such a section take it in full.
+    pub fn hit(&mut self, kind: &HitLocationType, damage: f64) -> f64 {
+        let damage = match self.hit_points.sections.iter().position(|section| { &section.kind == kind }) {
+            Some(i) => {
+                let counted = self.hit_points.sections[i].saturated_damage(self.taken[i], damage);
+                self.taken[i] += damage;
+                counted
+            }
+            None => damage,
+        };
+        self.total += damage;
+        damage
+    }
+
+    pub fn sunk(&self) -> bool {
+        self.total >= self.hit_points.health
+    }
+}
+
 #[derive(new, Serialize, Deserialize)]
 pub struct ShipConfiguration {
     pub artillery: Vec<Gun>,
-    pub geometry: Vec<ArmorFace>,
+    /// The secondary battery, which only fires at targets within its range
+    #[serde(default)]
+    pub secondaries: Vec<Gun>,
+    #[serde(default)]
+    pub torpedoes: Vec<TorpedoLauncher>,
+    /// Which of the ship's `armor` meshes this configuration has
+    #[serde(default)]
+    pub armor: usize,
+    /// That mesh, shared with every other configuration which has the same
+    /// armor. It's only stored once, on the ship, and linked back up when the
+    /// ship is loaded.
+    #[serde(skip)]
+    pub geometry: Arc<Vec<ArmorFace>>,
     pub speed: f64, // m/s
     pub length: f64, // m
     pub name: String,
+    /// Which module was chosen for each type of component, e.g.
+    /// "artillery" => "B_Artillery"
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    #[serde(default)]
+    pub hit_points: HitPoints,
+    /// The fraction of a torpedo's damage the torpedo protection belt absorbs
+    #[serde(default)]
+    pub torpedo_protection: f64,
 }
 
-#[derive(Serialize, Deserialize)]
+/// The component types whose choice of module changes what we simulate. A
+/// ship gets a configuration for every combination of them.
+pub const VARYING_COMPONENTS: [&str; 4] = ["artillery", "fireControl", "torpedoes", "atba"];
+
+impl ShipConfiguration {
+    /// A label telling this configuration apart from the ship's others, e.g.
+    /// "PACH207_Pensacola_1944 (B_Artillery, A_FireControl)"
+    pub fn label(&self) -> String {
+        let modules: Vec<_> = VARYING_COMPONENTS.iter().filter_map(|component_type| {
+            self.modules.get(*component_type).map(|module| { module.as_str() })
+        }).collect();
+        if modules.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, modules.join(", "))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ShipClass {
     Destroyer,
     Cruiser,
@@ -362,12 +656,67 @@ pub enum ShipClass {
     AircraftCarrier,
 }
 
+impl ShipClass {
+    /// Parses the class names the site uses, e.g. "aircarrier"
+    pub fn from_name(name: &str) -> Option<ShipClass> {
+        match name.to_lowercase().as_str() {
+            "destroyer" => Some(ShipClass::Destroyer),
+            "cruiser" => Some(ShipClass::Cruiser),
+            "battleship" => Some(ShipClass::Battleship),
+            "aircarrier" | "aircraftcarrier" | "carrier" => Some(ShipClass::AircraftCarrier),
+            _ => None,
+        }
+    }
+}
+
 #[derive(new, Serialize, Deserialize)]
+#[serde(from = "StoredShip")]
 pub struct Ship {
+    /// The site's vehicle id, e.g. "PASD014"
+    pub id: String,
+    /// Which country's vehicle list the ship came from. The vehicle page
+    /// doesn't say, so the importer fills this in.
+    #[new(default)]
+    pub nation: String,
     pub configurations: Vec<ShipConfiguration>,
-    tier: usize,
+    pub tier: usize,
     pub name: String,
     pub class: ShipClass,
+    /// The distinct armor meshes of the configurations. Exports may leave
+    /// them out.
+    pub armor: Vec<Arc<Vec<ArmorFace>>>,
+}
+
+/// A `Ship` as it's stored, before its configurations are linked up with
+/// their armor meshes. The fields have to match `Ship`'s.
+#[derive(Deserialize)]
+struct StoredShip {
+    id: String,
+    nation: String,
+    configurations: Vec<ShipConfiguration>,
+    tier: usize,
+    name: String,
+    class: ShipClass,
+    #[serde(default)]
+    armor: Vec<Arc<Vec<ArmorFace>>>,
+}
+
+impl From<StoredShip> for Ship {
+    fn from(stored: StoredShip) -> Ship {
+        let mut configurations = stored.configurations;
+        for config in configurations.iter_mut() {
+            config.geometry = stored.armor.get(config.armor).cloned().unwrap_or_default();
+        }
+        Ship {
+            id: stored.id,
+            nation: stored.nation,
+            configurations: configurations,
+            tier: stored.tier,
+            name: stored.name,
+            class: stored.class,
+            armor: stored.armor,
+        }
+    }
 }
 
 impl Ship {
@@ -396,3 +745,66 @@ impl Ship {
         return false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::plate;
+
+    fn launcher(arc: [f64; 2]) -> TorpedoLauncher {
+        TorpedoLauncher::new(vec!(), 4, 60.0, arc)
+    }
+
+    #[test]
+    fn torpedo_arcs() {
+        let starboard = launcher([30.0, 150.0]);
+        assert!(starboard.can_fire_at(30.0) && starboard.can_fire_at(90.0) && starboard.can_fire_at(150.0));
+        assert!(!starboard.can_fire_at(0.0) && !starboard.can_fire_at(-90.0) && !starboard.can_fire_at(180.0));
+        assert!(starboard.can_fire_at(90.0 + 360.0));
+
+        // Across the stern
+        let stern = launcher([150.0, -150.0]);
+        assert!(stern.can_fire_at(180.0) && stern.can_fire_at(-180.0) && stern.can_fire_at(160.0) && stern.can_fire_at(-160.0));
+        assert!(!stern.can_fire_at(0.0) && !stern.can_fire_at(90.0));
+
+        // All the way around, however it's written
+        for arc in [[-180.0, 180.0], [0.0, 360.0], [-200.0, 200.0]].iter() {
+            let all_round = launcher(*arc);
+            assert!([-180.0, -90.0, 0.0, 90.0, 180.0, 270.0].iter().all(|bearing| { all_round.can_fire_at(*bearing) }));
+        }
+    }
+
+    #[test]
+    fn torpedo_protection_absorbs_damage() {
+        let torpedo = TorpedoAmmo::new(10000.0, 30.0, 8000.0, 0.3);
+        let forward = Vector3::new(0.0, 0.0, 1.0);
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let mut target = plate(20.0, ArmorType::TorpedoProtectionBelt);
+        target.torpedo_protection = 0.4;
+        let (damage, impact) = torpedo.compute_damage(&target, 0.0, 0.0, forward, origin);
+        assert!((damage - 6000.0).abs() < 1e-6);
+        assert_eq!(impact, ImpactType::TorpedoProtection);
+        // Anything else takes it all
+        assert_eq!(torpedo.compute_damage(&plate(20.0, ArmorType::Normal), 0.0, 0.0, forward, origin), (10000.0, ImpactType::Penetration));
+    }
+
+    #[test]
+    fn overmatch() {
+        // 406mm overmatches up to 28mm
+        assert!(overmatches(0.406, 27.0));
+        assert!(!overmatches(0.406, 32.0));
+        assert!(overmatches(0.152, 10.0));
+        assert!(!overmatches(0.152, 25.0));
+    }
+
+    #[test]
+    fn ap_overmatches_thin_plates() {
+        let ap = ApAmmo::new(0.152, 3000.0, 0.033, 25.0, RicochetAngles::new(45.0, 60.0), 6.0);
+        // 80 degrees from the plate's normal always ricochets, unless the
+        // shell overmatches the plate
+        let angled = Vector3::new(80.0f64.to_radians().sin(), 0.0, 80.0f64.to_radians().cos());
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        assert_eq!(ap.compute_damage(&plate(25.0, ArmorType::Normal), 500.0, 500.0, angled, origin), (0.0, ImpactType::Ricochet));
+        assert_eq!(ap.compute_damage(&plate(10.0, ArmorType::Normal), 500.0, 500.0, angled, origin), (300.0, ImpactType::OverPenetration));
+    }
+}
@@ -0,0 +1,291 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::cache::{Cache, armor_models};
+use crate::datasource::DataSource;
+use crate::download::{bytes_fetched, revalidate};
+use crate::error::{Error, Result};
+use crate::gun::Ship;
+use crate::ship_parser::{armor_view_params, download_vehicle, vehicle_url};
+use crate::shiplist::{COUNTRIES, ShipListEntry, country_url};
+use crate::version::GameVersion;
+
+/// What happened to each vehicle during a bulk import
+#[derive(Default)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Vehicles we don't simulate, e.g. submarines
+    pub skipped: Vec<String>,
+    pub failures: Vec<(String, Error)>,
+}
+
+impl ImportReport {
+    pub fn log(&self) {
+        for (id, e) in self.failures.iter() {
+            warn!("Couldn't import {}: {}", id, e);
+        }
+        info!(
+            "Imported {} vehicles, skipped {}, {} failed",
+            self.imported.len(),
+            self.skipped.len(),
+            self.failures.len()
+        );
+    }
+
+    /// Returns true if any vehicle failed because it wasn't in the cache
+    pub fn has_missing_entries(&self) -> bool {
+        self.failures.iter().any(|(_, e)| {
+            match e {
+                Error::NotCached { .. } => true,
+                _ => false,
+            }
+        })
+    }
+}
+
+/// How far along a bulk import is
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    /// Bytes fetched from the network since the import started
+    pub bytes_fetched: u64,
+}
+
+/// Runs `job` on every id in `ids`, using `workers` threads, and returns the
+/// results in the same order as `ids`. An id whose worker panicked gets
+/// `None`. `progress` is called (on the calling thread) after each id
+/// finishes.
+fn run_parallel<T, J, F>(ids: &[String], workers: usize, job: J, mut progress: F) -> Vec<Option<T>>
+where
+    T: Send + 'static,
+    J: Fn(&str) -> T + Send + Sync + 'static,
+    F: FnMut(Progress),
+{
+    let ids = Arc::new(ids.to_vec());
+    let job = Arc::new(job);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    let bytes_at_start = bytes_fetched();
+
+    let handles: Vec<_> = (0..workers.max(1)).map(|_| {
+        let ids = ids.clone();
+        let job = job.clone();
+        let next = next.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= ids.len() {
+                    break;
+                }
+                if tx.send((i, job(&ids[i]))).is_err() {
+                    break;
+                }
+            }
+        })
+    }).collect();
+    drop(tx);
+
+    let mut results: Vec<_> = ids.iter().map(|_| { None }).collect();
+    let mut done = 0;
+    for (i, result) in rx {
+        results[i] = Some(result);
+        done += 1;
+        progress(Progress {
+            done: done,
+            total: ids.len(),
+            bytes_fetched: bytes_fetched() - bytes_at_start,
+        });
+    }
+    for handle in handles {
+        if handle.join().is_err() {
+            warn!("An import worker panicked");
+        }
+    }
+    results
+}
+
+fn worker_panicked(id: &str) -> Error {
+    // The worker handling it panicked, and already told us so
+    Error::WorkerPanicked { vehicle_id: id.to_string() }
+}
+
+/// Downloads and parses every vehicle in `entries`, using `workers` threads. A
+/// vehicle which fails to parse is recorded in the report, and the import
+/// carries on without it.
+///
+/// The vehicles come back in the same order as `entries`, no matter which worker
+/// finished first. `progress` is called (on the calling thread) after each
+/// vehicle finishes.
+pub fn import_vehicles<F: FnMut(Progress)>(source: Arc<dyn DataSource>, entries: &[ShipListEntry], workers: usize, progress: F) -> (Vec<Ship>, ImportReport) {
+    let ids: Vec<_> = entries.iter().map(|entry| { entry.id.clone() }).collect();
+    let results = run_parallel(&ids, workers, move |id| { download_vehicle(&*source, id) }, progress);
+
+    let mut vehicles = vec!();
+    let mut report = ImportReport::default();
+    for ((id, entry), result) in ids.iter().zip(entries).zip(results) {
+        match result {
+            Some(Ok(Some(mut vehicle))) => {
+                vehicle.nation = entry.nation.clone();
+                vehicles.push(vehicle);
+                report.imported.push(id.to_string());
+            }
+            Some(Ok(None)) => {
+                report.skipped.push(id.to_string());
+            }
+            Some(Err(e)) => {
+                report.failures.push((id.to_string(), e));
+            }
+            None => {
+                report.failures.push((id.to_string(), worker_panicked(id)));
+            }
+        }
+    }
+    (vehicles, report)
+}
+
+/// What a revalidation pass found
+#[derive(Default)]
+pub struct RevalidationReport {
+    /// Vehicles whose page or armor models changed on the site
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub failures: Vec<(String, Error)>,
+}
+
+impl RevalidationReport {
+    pub fn log(&self) {
+        for (id, e) in self.failures.iter() {
+            warn!("Couldn't revalidate {}: {}", id, e);
+        }
+        for id in self.changed.iter() {
+            info!("{} has changed", id);
+        }
+        info!(
+            "Revalidated {} vehicles, {} changed, {} failed",
+            self.changed.len() + self.unchanged.len(),
+            self.changed.len(),
+            self.failures.len()
+        );
+    }
+}
+
+/// Adds index entries for a vehicle's page, armor views and armor models if
+/// they were cached before the index existed, so that `Cache::vehicle_hashes`
+/// finds them. The URLs are worked out from the cached pages themselves.
+pub fn backfill_vehicle_index(cache: &Cache, version: &GameVersion, vehicle_id: &str) -> Result<()> {
+    let url = vehicle_url(vehicle_id);
+    let page = match cache.backfill(&url, None, None)? {
+        Some(entry) => cache.read(&entry.hash)?,
+        None => { return Ok(()); }
+    };
+    let views = match armor_view_params(&url, &page) {
+        Ok(views) => views,
+        Err(e) => {
+            warn!("Couldn't work out the armor views of {}: {}", vehicle_id, e);
+            return Ok(());
+        }
+    };
+    for params in views {
+        if let Some(view) = cache.backfill(&url, Some("armor"), Some(&params))? {
+            for model in armor_models(&cache.read(&view.hash)?) {
+                cache.backfill(&format!("{}/armor/{}", version.data_url(), model), None, None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Asks the site whether each cached country page has changed, refreshing any
+/// which did, so the ship list isn't read from stale pages. Pages which
+/// aren't cached yet are left for the ship list to fetch. Returns the
+/// countries whose pages changed.
+pub fn refresh_ship_list(cache: &Cache) -> Result<Vec<String>> {
+    let mut changed = vec!();
+    for country in COUNTRIES.iter() {
+        if let Some(entry) = cache.backfill(&country_url(country), None, None)? {
+            if revalidate(cache, &entry)? {
+                changed.push(country.to_string());
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Asks the site whether anything we've cached for each vehicle in `ids` has
+/// changed, refreshing whatever did. Armor models shared between vehicles are
+/// only checked once.
+pub fn find_changed_vehicles<F: FnMut(Progress)>(cache: Arc<Cache>, version: &GameVersion, ids: &[String], workers: usize, progress: F) -> RevalidationReport {
+    let checked: Arc<Mutex<HashMap<String, bool>>> = Arc::new(Mutex::new(HashMap::new()));
+    let version = version.clone();
+    let results = run_parallel(ids, workers, move |id| {
+        backfill_vehicle_index(&cache, &version, id)?;
+        let mut changed = false;
+        for hash in cache.vehicle_hashes(id) {
+            let known = checked.lock().unwrap().get(&hash).cloned();
+            let hash_changed = match known {
+                Some(hash_changed) => hash_changed,
+                None => {
+                    let entry = match cache.entry(&hash) {
+                        Some(entry) => entry,
+                        None => { continue; }
+                    };
+                    let hash_changed = revalidate(&cache, &entry)?;
+                    checked.lock().unwrap().insert(hash, hash_changed);
+                    hash_changed
+                }
+            };
+            changed |= hash_changed;
+        }
+        Ok(changed)
+    }, progress);
+
+    let mut report = RevalidationReport::default();
+    for (id, result) in ids.iter().zip(results) {
+        match result {
+            Some(Ok(true)) => { report.changed.push(id.to_string()); }
+            Some(Ok(false)) => { report.unchanged.push(id.to_string()); }
+            Some(Err(e)) => { report.failures.push((id.to_string(), e)); }
+            None => { report.failures.push((id.to_string(), worker_panicked(id))); }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{url_hash, params_hash};
+    use crate::test_util::{fixtures_dir, TempDir};
+    use std::fs;
+
+    #[test]
+    fn backfills_caches_from_before_the_index() {
+        let fixtures = fixtures_dir();
+        let dir = TempDir::new("backfill");
+
+        // Lay the files out the way an old cache did: plain text, no index
+        let version = GameVersion::current();
+        let url = vehicle_url("pasd014");
+        let copy = |file: &str, hash: String| { fs::copy(fixtures.join(file), dir.join(hash)).unwrap(); };
+        copy("pasd014.html", url_hash(&url));
+        copy("pasd014_armor.html", params_hash(&url, "armor", r#"{"artillery":"A_Artillery","hull":"A_Hull"}"#));
+        copy("pasd014_armor_b.html", params_hash(&url, "armor", r#"{"artillery":"B_Artillery","hull":"A_Hull"}"#));
+        for model in ["pasd014_hull.json", "pasd014_turret.json"].iter() {
+            copy(model, url_hash(&format!("{}/armor/{}", version.data_url(), model)));
+        }
+
+        let cache = Cache::open(dir.path());
+        assert!(cache.vehicle_hashes("pasd014").is_empty());
+        backfill_vehicle_index(&cache, &version, "pasd014").unwrap();
+        assert_eq!(cache.vehicle_hashes("pasd014").len(), 5);
+
+        // The index survives reopening
+        assert_eq!(Cache::open(dir.path()).vehicle_hashes("pasd014").len(), 5);
+    }
+}
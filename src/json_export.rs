@@ -0,0 +1,513 @@
+use cgmath::Point3;
+use serde_derive::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::ballistics::{Ballistics, Dispersion};
+use crate::error::{Error, Result};
+use crate::gun::*;
+use crate::version::GameVersion;
+
+const JSON_FORMAT: &str = "wows_armor_ships";
+/// Bump whenever the layout of the JSON changes in a way older builds can't
+/// read. The layout is defined by the `*Json` structs below rather than the
+/// ship structs, so it only changes when they do; fields added since are
+/// optional, so files without them still import.
+///
+/// 1, 2: the ship structs as they were at the time
+/// 3: armor meshes are stored once per ship, rather than per configuration
+const JSON_FORMAT_VERSION: u32 = 3;
+
+/// The top level of an exported file
+#[derive(Serialize, Deserialize)]
+struct ShipsJson {
+    format: String,
+    format_version: u32,
+    /// `SCHEMA_VERSION` of the exporting build, for reference
+    #[serde(default)]
+    schema_version: u32,
+    game_version: GameVersion,
+    /// Whether the `armor` of each ship was included
+    armor: bool,
+    ships: Vec<ShipJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShipJson {
+    id: String,
+    nation: String,
+    name: String,
+    tier: usize,
+    class: ShipClass,
+    configurations: Vec<ConfigurationJson>,
+    /// Each distinct armor mesh, or nothing if the export left them out
+    #[serde(default)]
+    armor: Vec<Vec<ArmorFaceJson>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConfigurationJson {
+    name: String,
+    speed: f64,
+    length: f64,
+    artillery: Vec<GunJson>,
+    #[serde(default)]
+    secondaries: Vec<GunJson>,
+    #[serde(default)]
+    torpedoes: Vec<TorpedoLauncherJson>,
+    /// Index into the ship's `armor`
+    #[serde(default)]
+    armor: usize,
+    #[serde(default)]
+    hit_points: HitPointsJson,
+    #[serde(default)]
+    modules: BTreeMap<String, String>,
+    /// Added after format version 3 was introduced, so optional
+    #[serde(default)]
+    torpedo_protection: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GunJson {
+    dispersion: DispersionJson,
+    range: f64,
+    reload: f64,
+    /// Added after format version 3 was introduced, so optional
+    #[serde(default = "one_barrel")]
+    barrels: usize,
+    ammo: Vec<AmmoJson>,
+}
+
+fn one_barrel() -> usize {
+    1
+}
+
+#[derive(Serialize, Deserialize)]
+struct DispersionJson {
+    ideal_radius: f64,
+    min_radius: f64,
+    ideal_distance: f64,
+    taper_distance: f64,
+    radius_on_zero: f64,
+    radius_on_delim: f64,
+    radius_on_max: f64,
+    delim: f64,
+    max_range: f64,
+    sigma: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AmmoJson {
+    bullet: BulletJson,
+    ballistics: BallisticsJson,
+}
+
+#[derive(Serialize, Deserialize)]
+enum BulletJson {
+    He { damage: f64, piercing: f64 },
+    Ap { diameter: f64, damage: f64, detonator: f64, detonator_threshold: f64, ricochet: RicochetJson, normalization: f64 },
+    Sap { diameter: f64, damage: f64, piercing: f64, ricochet: RicochetJson },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RicochetJson {
+    ricochet_at: f64,
+    always_ricochet_at: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BallisticsJson {
+    mass: f64,
+    diameter: f64,
+    muzzle_speed: f64,
+    drag: f64,
+    krupp: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TorpedoLauncherJson {
+    ammo: Vec<TorpedoJson>,
+    barrels: usize,
+    reload: f64,
+    arc: [f64; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+struct TorpedoJson {
+    damage: f64,
+    speed: f64,
+    range: f64,
+    flooding: f64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HitPointsJson {
+    health: f64,
+    sections: Vec<HitLocationJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HitLocationJson {
+    name: String,
+    kind: HitLocationType,
+    max_hp: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArmorFaceJson {
+    vertices: [PointJson; 3],
+    thickness: f64,
+    armor_type: ArmorTypeJson,
+}
+
+/// A copy of `ArmorType`, so that changes to it don't change the layout
+#[derive(Serialize, Deserialize)]
+enum ArmorTypeJson {
+    Normal,
+    Citadel,
+    TorpedoProtectionBelt,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PointJson {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+impl GunJson {
+    fn from_gun(gun: &Gun) -> GunJson {
+        let d = &gun.dispersion;
+        GunJson {
+            dispersion: DispersionJson {
+                ideal_radius: d.ideal_radius,
+                min_radius: d.min_radius,
+                ideal_distance: d.ideal_distance,
+                taper_distance: d.taper_distance,
+                radius_on_zero: d.radius_on_zero,
+                radius_on_delim: d.radius_on_delim,
+                radius_on_max: d.radius_on_max,
+                delim: d.delim,
+                max_range: d.max_range,
+                sigma: d.sigma,
+            },
+            range: gun.range,
+            reload: gun.reload,
+            barrels: gun.barrels,
+            ammo: gun.ammo.iter().map(|ammo| {
+                let bullet = match &ammo.bullet {
+                    AmmoType::He(he) => BulletJson::He { damage: he.damage, piercing: he.piercing },
+                    AmmoType::Ap(ap) => BulletJson::Ap {
+                        diameter: ap.diameter,
+                        damage: ap.damage,
+                        detonator: ap.detonator,
+                        detonator_threshold: ap.detonator_threshold,
+                        ricochet: RicochetJson::from_angles(&ap.ricochet),
+                        normalization: ap.normalization,
+                    },
+                    AmmoType::Sap(sap) => BulletJson::Sap {
+                        diameter: sap.diameter,
+                        damage: sap.damage,
+                        piercing: sap.piercing,
+                        ricochet: RicochetJson::from_angles(&sap.ricochet),
+                    },
+                };
+                let b = &ammo.ballistics;
+                AmmoJson {
+                    bullet: bullet,
+                    ballistics: BallisticsJson { mass: b.mass, diameter: b.diameter, muzzle_speed: b.muzzle_speed, drag: b.drag, krupp: b.krupp },
+                }
+            }).collect(),
+        }
+    }
+
+    fn into_gun(self) -> Gun {
+        let d = self.dispersion;
+        let dispersion = Dispersion::new(
+            d.ideal_radius, d.min_radius, d.ideal_distance, d.taper_distance, d.radius_on_zero,
+            d.radius_on_delim, d.radius_on_max, d.delim, d.max_range, d.sigma,
+        );
+        let ammo = self.ammo.into_iter().map(|ammo| {
+            let bullet = match ammo.bullet {
+                BulletJson::He { damage, piercing } => AmmoType::He(HeAmmo::new(damage, piercing)),
+                BulletJson::Ap { diameter, damage, detonator, detonator_threshold, ricochet, normalization } => {
+                    AmmoType::Ap(ApAmmo::new(diameter, damage, detonator, detonator_threshold, ricochet.into_angles(), normalization))
+                }
+                BulletJson::Sap { diameter, damage, piercing, ricochet } => {
+                    AmmoType::Sap(SapAmmo::new(diameter, damage, piercing, ricochet.into_angles()))
+                }
+            };
+            let b = ammo.ballistics;
+            Ammo::new(bullet, Ballistics::new(b.mass, b.diameter, b.muzzle_speed, b.drag, b.krupp))
+        }).collect();
+        Gun::new(dispersion, ammo, self.range, self.reload, self.barrels)
+    }
+}
+
+impl RicochetJson {
+    fn from_angles(angles: &RicochetAngles) -> RicochetJson {
+        RicochetJson { ricochet_at: angles.ricochet_at, always_ricochet_at: angles.always_ricochet_at }
+    }
+
+    fn into_angles(self) -> RicochetAngles {
+        RicochetAngles::new(self.ricochet_at, self.always_ricochet_at)
+    }
+}
+
+impl TorpedoLauncherJson {
+    fn from_launcher(launcher: &TorpedoLauncher) -> TorpedoLauncherJson {
+        TorpedoLauncherJson {
+            ammo: launcher.ammo.iter().map(|torpedo| {
+                TorpedoJson { damage: torpedo.damage, speed: torpedo.speed, range: torpedo.range, flooding: torpedo.flooding }
+            }).collect(),
+            barrels: launcher.barrels,
+            reload: launcher.reload,
+            arc: launcher.arc,
+        }
+    }
+
+    fn into_launcher(self) -> TorpedoLauncher {
+        let ammo = self.ammo.into_iter().map(|torpedo| {
+            TorpedoAmmo::new(torpedo.damage, torpedo.speed, torpedo.range, torpedo.flooding)
+        }).collect();
+        TorpedoLauncher::new(ammo, self.barrels, self.reload, self.arc)
+    }
+}
+
+impl HitPointsJson {
+    fn from_hit_points(hit_points: &HitPoints) -> HitPointsJson {
+        HitPointsJson {
+            health: hit_points.health,
+            sections: hit_points.sections.iter().map(|section| {
+                HitLocationJson { name: section.name.clone(), kind: section.kind.clone(), max_hp: section.max_hp }
+            }).collect(),
+        }
+    }
+
+    fn into_hit_points(self) -> HitPoints {
+        let sections = self.sections.into_iter().map(|section| {
+            HitLocation::new(section.name, section.kind, section.max_hp)
+        }).collect();
+        HitPoints::new(self.health, sections)
+    }
+}
+
+impl ArmorFaceJson {
+    fn from_face(face: &ArmorFace) -> ArmorFaceJson {
+        let point = |p: &Point3<f64>| { PointJson { x: p.x, y: p.y, z: p.z } };
+        ArmorFaceJson {
+            vertices: [point(&face.vertices[0]), point(&face.vertices[1]), point(&face.vertices[2])],
+            thickness: face.thickness,
+            armor_type: match face.armor_type {
+                ArmorType::Normal => ArmorTypeJson::Normal,
+                ArmorType::Citadel => ArmorTypeJson::Citadel,
+                ArmorType::TorpedoProtectionBelt => ArmorTypeJson::TorpedoProtectionBelt,
+            },
+        }
+    }
+
+    fn into_face(self) -> ArmorFace {
+        let [a, b, c] = self.vertices;
+        let point = |p: PointJson| { Point3::new(p.x, p.y, p.z) };
+        let armor_type = match self.armor_type {
+            ArmorTypeJson::Normal => ArmorType::Normal,
+            ArmorTypeJson::Citadel => ArmorType::Citadel,
+            ArmorTypeJson::TorpedoProtectionBelt => ArmorType::TorpedoProtectionBelt,
+        };
+        ArmorFace::new([point(a), point(b), point(c)], self.thickness, armor_type)
+    }
+}
+
+impl ShipJson {
+    fn from_ship(ship: &Ship, include_armor: bool) -> ShipJson {
+        let guns = |guns: &[Gun]| -> Vec<GunJson> { guns.iter().map(GunJson::from_gun).collect() };
+        ShipJson {
+            id: ship.id.clone(),
+            nation: ship.nation.clone(),
+            name: ship.name.clone(),
+            tier: ship.tier,
+            class: ship.class,
+            configurations: ship.configurations.iter().map(|config| {
+                ConfigurationJson {
+                    name: config.name.clone(),
+                    speed: config.speed,
+                    length: config.length,
+                    artillery: guns(&config.artillery),
+                    secondaries: guns(&config.secondaries),
+                    torpedoes: config.torpedoes.iter().map(TorpedoLauncherJson::from_launcher).collect(),
+                    armor: config.armor,
+                    hit_points: HitPointsJson::from_hit_points(&config.hit_points),
+                    modules: config.modules.clone(),
+                    torpedo_protection: config.torpedo_protection,
+                }
+            }).collect(),
+            armor: if include_armor {
+                ship.armor.iter().map(|mesh| { mesh.iter().map(ArmorFaceJson::from_face).collect() }).collect()
+            } else {
+                vec!()
+            },
+        }
+    }
+
+    /// Builds the ship, linking each configuration up with its armor mesh
+    fn into_ship(self) -> std::result::Result<Ship, String> {
+        let armor: Vec<Arc<Vec<ArmorFace>>> = self.armor.into_iter().map(|mesh| {
+            Arc::new(mesh.into_iter().map(ArmorFaceJson::into_face).collect())
+        }).collect();
+        let mut configurations = vec!();
+        for config in self.configurations {
+            // Without meshes the configurations just have no armor
+            let geometry = if armor.is_empty() {
+                Arc::new(vec!())
+            } else {
+                match armor.get(config.armor) {
+                    Some(mesh) => mesh.clone(),
+                    None => {
+                        return Err(format!("{} ({}) has armor mesh {}, but there are only {}", self.id, config.name, config.armor, armor.len()));
+                    }
+                }
+            };
+            configurations.push(ShipConfiguration::new(
+                config.artillery.into_iter().map(GunJson::into_gun).collect(),
+                config.secondaries.into_iter().map(GunJson::into_gun).collect(),
+                config.torpedoes.into_iter().map(TorpedoLauncherJson::into_launcher).collect(),
+                config.armor,
+                geometry,
+                config.speed,
+                config.length,
+                config.name,
+                config.modules,
+                config.hit_points.into_hit_points(),
+                config.torpedo_protection,
+            ));
+        }
+        let mut ship = Ship::new(self.id, configurations, self.tier, self.name, self.class, armor);
+        ship.nation = self.nation;
+        Ok(ship)
+    }
+}
+
+/// Writes the vehicles to `path` as JSON. Armor meshes are large, so they're
+/// left out (as empty `armor` lists) unless `include_armor` is set.
+pub fn export_json<P: AsRef<Path>>(path: P, version: &GameVersion, vehicles: &[Ship], include_armor: bool) -> Result<()> {
+    let path = path.as_ref();
+    let json = ShipsJson {
+        format: JSON_FORMAT.to_string(),
+        format_version: JSON_FORMAT_VERSION,
+        schema_version: SCHEMA_VERSION,
+        game_version: version.clone(),
+        armor: include_armor,
+        ships: vehicles.iter().map(|ship| { ShipJson::from_ship(ship, include_armor) }).collect(),
+    };
+    let f = fs::File::create(path)?;
+    serde_json::to_writer_pretty(f, &json).map_err(|e| {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Decode { url: path.display().to_string(), reason: e.to_string() }
+        }
+    })
+}
+
+/// Reads vehicles back from a file written by `export_json` (and possibly
+/// edited since). Returns the game version the file says it's for, too.
+pub fn import_json<P: AsRef<Path>>(path: P) -> Result<(GameVersion, Vec<Ship>)> {
+    let path = path.as_ref();
+    let bad_json = |reason: String| {
+        Error::Decode { url: path.display().to_string(), reason: reason }
+    };
+
+    // Check the header before trying to read the ships, so that a file from
+    // a newer build gets a clear error
+    let text = fs::read_to_string(path)?;
+    let header: serde_json::Value = serde_json::from_str(&text).map_err(|e| { bad_json(e.to_string()) })?;
+    if header["format"] != JSON_FORMAT {
+        return Err(bad_json(format!("Not a ships export (format is {})", header["format"])));
+    }
+    let format_version = header["format_version"].as_u64().unwrap_or(0);
+    if format_version != JSON_FORMAT_VERSION as u64 {
+        return Err(bad_json(format!(
+            "Format version {} isn't supported (expected {})",
+            format_version, JSON_FORMAT_VERSION
+        )));
+    }
+
+    let json: ShipsJson = serde_json::from_value(header).map_err(|e| { bad_json(format!("Couldn't decode ships: {}", e)) })?;
+    let mut ships = vec!();
+    for ship in json.ships {
+        ships.push(ship.into_ship().map_err(bad_json)?);
+    }
+    Ok((json.game_version, ships))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ship_parser::download_vehicle;
+    use crate::test_util::{fixtures, fixtures_dir, TempDir};
+
+    #[test]
+    fn round_trip() {
+        let ship = download_vehicle(&fixtures(), "pasd014").unwrap().unwrap();
+        let dir = TempDir::new("json");
+        let path = dir.join("ships.json");
+
+        export_json(&path, &GameVersion::current(), &[ship], false).unwrap();
+        let (version, ships) = import_json(&path).unwrap();
+        assert_eq!(version, GameVersion::current());
+        assert_eq!(ships.len(), 1);
+        assert_eq!(ships[0].id, "pasd014");
+        assert_eq!(ships[0].configurations.len(), 8);
+        assert!(ships[0].configurations[0].geometry.is_empty());
+        assert_eq!(ships[0].configurations[0].torpedoes[0].barrels, 5);
+        assert_eq!(ships[0].configurations[0].artillery[0].barrels, 2);
+        assert!((ships[0].configurations[0].torpedo_protection - 0.4).abs() < 1e-9);
+    }
+
+    /// Files exported before guns had `barrels` still import, as single
+    /// barrelled guns, and before configurations had `torpedo_protection`, as
+    /// unprotected
+    #[test]
+    fn imports_older_files() {
+        let (version, ships) = import_json(fixtures_dir().join("ships_format3.json")).unwrap();
+        assert_eq!(version, GameVersion::current());
+        assert_eq!(ships[0].id, "pasd014");
+        assert_eq!(ships[0].configurations.len(), 2);
+        assert_eq!(ships[0].configurations[0].artillery[0].barrels, 1);
+        assert_eq!(ships[0].configurations[0].torpedo_protection, 0.0);
+    }
+
+    #[test]
+    fn round_trip_with_armor() {
+        let ship = download_vehicle(&fixtures(), "pasd014").unwrap().unwrap();
+        let faces = ship.armor[1].len();
+        let dir = TempDir::new("json");
+        let path = dir.join("ships.json");
+
+        export_json(&path, &GameVersion::current(), &[ship], true).unwrap();
+        let (_, ships) = import_json(&path).unwrap();
+        assert_eq!(ships[0].armor.len(), 2);
+        assert_eq!(ships[0].configurations[7].geometry.len(), faces);
+        assert!(Arc::ptr_eq(&ships[0].configurations[4].geometry, &ships[0].configurations[7].geometry));
+
+        // A configuration pointing past the meshes is an error, not a ship
+        // without armor
+        let text = fs::read_to_string(&path).unwrap().replace("\"armor\": 0", "\"armor\": 7");
+        fs::write(&path, text).unwrap();
+        assert!(import_json(&path).is_err());
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let dir = TempDir::new("json");
+        let path = dir.join("ships.json");
+        fs::write(&path, r#"{"format": "wows_armor_ships", "format_version": 4, "game_version": "current", "armor": false, "ships": []}"#).unwrap();
+        assert!(import_json(&path).is_err());
+        fs::write(&path, r#"{"format": "something_else", "format_version": 3, "game_version": "current", "armor": false, "ships": []}"#).unwrap();
+        assert!(import_json(&path).is_err());
+        fs::write(&path, r#"{"format": "wows_armor_ships", "format_version": 3, "game_version": "current", "armor": false, "ships": []}"#).unwrap();
+        assert!(import_json(&path).unwrap().1.is_empty());
+    }
+}
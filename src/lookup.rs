@@ -0,0 +1,165 @@
+use crate::gun::Ship;
+use crate::store::StoreEntry;
+
+/// Anything which can be looked up by vehicle id and name: parsed ships, or
+/// the entries of a ship store's index.
+pub trait Named {
+    fn id(&self) -> &str;
+    fn name(&self) -> &str;
+}
+
+impl Named for Ship {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Named for StoreEntry {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Finds the vehicle with the given id. Ids are compared ignoring case, since
+/// the site isn't consistent about it ("PASD014" vs "pasd014").
+pub fn find_by_id<'a, T: Named>(items: &'a [T], id: &str) -> Option<&'a T> {
+    items.iter().find(|item| { item.id().eq_ignore_ascii_case(id) })
+}
+
+/// Finds the vehicle with exactly the given name
+pub fn find_by_name<'a, T: Named>(items: &'a [T], name: &str) -> Option<&'a T> {
+    items.iter().find(|item| { item.name() == name })
+}
+
+/// Lowercases and drops anything that isn't a letter or digit, so that
+/// "Admiral Graf Spee" matches "admiral graf-spee"
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| { c.is_alphanumeric() || c.is_whitespace() }).flat_map(|c| { c.to_lowercase() }).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// How well `name` matches `query`, lower is better. `None` if it doesn't
+/// match at all.
+fn match_score(name: &str, query: &str) -> Option<usize> {
+    let name = normalize(name);
+    let squashed: String = name.split_whitespace().collect();
+    let query_squashed: String = query.split_whitespace().collect();
+    if name == query {
+        return Some(0);
+    }
+    if name.starts_with(query) {
+        return Some(1);
+    }
+    if squashed.contains(&query_squashed) {
+        return Some(2);
+    }
+
+    // Allow a typo or two, in the whole name or any one word of it
+    let max_distance = if query.chars().count() > 5 { 2 } else { 1 };
+    let distance = name.split_whitespace().chain(std::iter::once(name.as_str())).map(|word| {
+        edit_distance(word, query)
+    }).min().unwrap_or(usize::MAX);
+    if distance <= max_distance {
+        Some(3 + distance)
+    } else {
+        None
+    }
+}
+
+/// Finds vehicles whose names look like `query`, ignoring case and
+/// punctuation and allowing for small typos. The best matches come first. A
+/// query with nothing to match on matches nothing.
+pub fn search_by_name<'a, T: Named>(items: &'a [T], query: &str) -> Vec<&'a T> {
+    let query = normalize(query);
+    let query = query.trim();
+    if query.is_empty() {
+        return vec!();
+    }
+    let mut matches: Vec<_> = items.iter().filter_map(|item| {
+        match_score(item.name(), query).map(|score| { (score, item) })
+    }).collect();
+    matches.sort_by_key(|(score, _)| { *score });
+    matches.into_iter().map(|(_, item)| { item }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Vehicle(&'static str, &'static str);
+
+    impl Named for Vehicle {
+        fn id(&self) -> &str {
+            self.0
+        }
+
+        fn name(&self) -> &str {
+            self.1
+        }
+    }
+
+    const VEHICLES: [Vehicle; 4] = [
+        Vehicle("PGSC106", "Admiral Graf Spee"),
+        Vehicle("PASC008", "Pensacola"),
+        Vehicle("PASC006", "Dallas"),
+        Vehicle("PASB018", "Iowa"),
+    ];
+
+    fn search(query: &str) -> Vec<&'static str> {
+        search_by_name(&VEHICLES, query).into_iter().map(|vehicle| { vehicle.0 }).collect()
+    }
+
+    #[test]
+    fn finds_by_id_ignoring_case() {
+        assert_eq!(find_by_id(&VEHICLES, "pasc008").map(|vehicle| { vehicle.1 }), Some("Pensacola"));
+        assert!(find_by_id(&VEHICLES, "pasc009").is_none());
+    }
+
+    #[test]
+    fn scores_matches() {
+        assert_eq!(match_score("Pensacola", "pensacola"), Some(0));
+        assert_eq!(match_score("Pensacola", "pensa"), Some(1));
+        assert_eq!(match_score("Admiral Graf Spee", "grafspee"), Some(2));
+        assert_eq!(match_score("Pensacola", "pensacla"), Some(4));
+        assert_eq!(match_score("Admiral Graf Spee", "sper"), Some(4));
+        assert_eq!(match_score("Pensacola", "iowa"), None);
+    }
+
+    #[test]
+    fn searches_by_name() {
+        assert_eq!(search("Pensacola"), vec!["PASC008"]);
+        assert_eq!(search("  PENSA "), vec!["PASC008"]);
+        assert_eq!(search("Admiral Graf-Spee"), vec!["PGSC106"]);
+        assert_eq!(search("graf spee"), vec!["PGSC106"]);
+        assert_eq!(search("Dalas"), vec!["PASC006"]);
+        assert!(search("Yamato").is_empty());
+    }
+
+    #[test]
+    fn empty_queries_match_nothing() {
+        assert!(search("").is_empty());
+        assert!(search("  ").is_empty());
+        assert!(search("-").is_empty());
+    }
+}
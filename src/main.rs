@@ -1,19 +1,48 @@
 #[macro_use]
 extern crate derive_new;
+#[macro_use]
+extern crate lazy_static;
 
-use log::{info, debug};
+use log::{info, debug, warn, error};
 use std::collections::HashMap;
+use std::sync::Arc;
 use cgmath::{Vector3, Point3};
-use std::time::{Instant};
+use std::time::{Instant, Duration};
+use regex::Regex;
 
 mod shiplist;
 mod download;
+mod cache;
 mod ballistics;
 mod gun;
 mod ship_parser;
-use crate::shiplist::get_ship_list;
+mod error;
+mod import;
+mod datasource;
+mod version;
+mod bundle;
+mod database;
+mod store;
+mod lookup;
+mod json_export;
+mod materials;
+mod util;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+mod test_util;
+use crate::shiplist::{get_ship_list, ShipListEntry};
 use crate::gun::*;
 use crate::ballistics::Dispersion;
+use crate::import::{backfill_vehicle_index, find_changed_vehicles, refresh_ship_list};
+use crate::database::{load_database, save_database, update_database};
+use crate::datasource::{DataSource, HttpSource, CacheSource, FixtureSource};
+use crate::cache::Cache;
+use crate::version::GameVersion;
+use crate::bundle::{BundleFilter, export_bundle, import_bundle};
+use crate::store::{ShipStore, write_store};
+use crate::lookup::{find_by_id, find_by_name, search_by_name};
+use crate::json_export::{export_json, import_json};
 use crate::ship_parser::download_vehicle;
 
 fn deg2rad(x: f64) -> f64 {
@@ -47,57 +76,575 @@ fn volley(count: usize, dispersion: &Dispersion, gun: &Ammo, target: &ShipConfig
     (total_damage / count as f64, map)
 }
 
+/// The average damage `attacker` deals to `target` in a minute of firing,
+/// from `count` simulated shots per gun with each gun's first ammo. Every
+/// barrel of a gun fires each salvo. Secondary guns only join in when the
+/// target is within their range.
+fn damage_per_minute(count: usize, attacker: &ShipConfiguration, target: &ShipConfiguration, range: f64, azimuth: f64, offset: Point3<f64>) -> f64 {
+    let secondaries = attacker.secondaries.iter().filter(|gun| { gun.range >= range });
+    attacker.artillery.iter().filter(|gun| { gun.range >= range }).chain(secondaries).filter_map(|gun| {
+        let ammo = gun.ammo.get(0)?;
+        let (damage, _) = volley(count, &gun.dispersion, ammo, target, range, azimuth, offset);
+        Some(damage * gun.barrels as f64 * 60.0 / gun.reload)
+    }).sum()
+}
+
+/// How many salvos of `attacker`'s main battery (each gun's first ammo) it takes
+/// to sink `target`, with its sections saturating. Penetrations go to the
+/// casemate, since the armor doesn't say where along the ship a hit landed.
+/// `None` if it's still afloat after `max_salvos`.
+fn salvos_to_sink(attacker: &ShipConfiguration, target: &ShipConfiguration, range: f64, azimuth: f64, max_salvos: usize) -> Option<usize> {
+    let mut tracker = DamageTracker::new(&target.hit_points);
+    for salvo in 0..max_salvos {
+        for gun in attacker.artillery.iter() {
+            let ammo = match gun.ammo.get(0) {
+                Some(ammo) => { ammo }
+                None => { continue; }
+            };
+            for _ in 0..gun.barrels {
+                let (damage, impact) = take_shot(&gun.dispersion, ammo, target, range, azimuth, Point3::new(0.0, 0.0, 0.0));
+                let section = match impact {
+                    ImpactType::Citadel => HitLocationType::Citadel,
+                    _ => HitLocationType::Casemate,
+                };
+                tracker.hit(&section, damage);
+            }
+        }
+        if tracker.sunk() {
+            return Some(salvo + 1);
+        }
+    }
+    None
+}
+
+/// Estimates one spread from every launcher of `attacker` that can fire at a
+/// target `bearing` degrees off its bow and `range` meters away, assuming every
+/// torpedo hits. Hits are spread evenly along the target's length, arriving
+/// from `azimuth`. Returns the average damage and the expected floods.
+fn torpedo_estimate(attacker: &ShipConfiguration, target: &ShipConfiguration, range: f64, bearing: f64, azimuth: f64) -> (f64, f64) {
+    const SAMPLES: usize = 20;
+    let zs = target.geometry.iter().flat_map(|face| { face.vertices.iter() }).map(|p| { p.z });
+    let (min_z, max_z) = zs.fold((1./0., -1./0.), |(min, max): (f64, f64), z| { (min.min(z), max.max(z)) });
+    if min_z > max_z {
+        return (0.0, 0.0);
+    }
+    let direction = Vector3::new(deg2rad(azimuth).sin(), 0.0, deg2rad(azimuth).cos());
+
+    let mut damage = 0.0;
+    let mut floods = 0.0;
+    for launcher in attacker.torpedoes.iter().filter(|launcher| { launcher.can_fire_at(bearing) }) {
+        let torpedo = match launcher.ammo.get(0) {
+            Some(torpedo) if torpedo.range >= range => { torpedo }
+            _ => { continue; }
+        };
+        for i in 0..SAMPLES {
+            let z = min_z + (max_z - min_z) * (i as f64 + 0.5) / SAMPLES as f64;
+            let (hit, impact) = torpedo.compute_damage(target, 0.0, torpedo.speed, direction, Point3::new(0.0, 0.0, z));
+            damage += hit * launcher.barrels as f64 / SAMPLES as f64;
+            if impact != ImpactType::Miss {
+                floods += torpedo.flooding * launcher.barrels as f64 / SAMPLES as f64;
+            }
+        }
+    }
+    (damage, floods)
+}
+
+/// Returns the argument following `flag`, if it was given
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.windows(2).find(|pair| { pair[0] == flag }).map(|pair| { pair[1].as_str() })
+}
+
+/// Returns the argument following every occurrence of `flag`
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.windows(2).filter(|pair| { pair[0] == flag }).map(|pair| { pair[1].clone() }).collect()
+}
+
+/// Handles the `--retries N`, `--rate-limit N` and `--user-agent UA` flags,
+/// on top of whatever the environment set.
+fn configure_downloads(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = download::DownloadConfig::from_env();
+    for pair in args.windows(2) {
+        match pair[0].as_str() {
+            "--retries" => { config.retries = pair[1].parse()?; }
+            "--rate-limit" => { config.rate_limit = pair[1].parse()?; }
+            "--user-agent" => { config.user_agent = pair[1].clone(); }
+            _ => {}
+        }
+    }
+    download::configure(config);
+    Ok(())
+}
+
+/// Handles the `--expire-days N`, `--expire-url REGEX` and `--expire-vehicle ID`
+/// flags, which may each be given multiple times.
+fn expire_cache(args: &[String], cache: &Cache, version: &GameVersion) -> Result<(), Box<dyn std::error::Error>> {
+    for pair in args.windows(2) {
+        let expired = match pair[0].as_str() {
+            "--expire-days" => {
+                let days: u64 = pair[1].parse()?;
+                cache.expire_older_than(Duration::from_secs(days * 24 * 60 * 60))?
+            }
+            "--expire-url" => { cache.expire_matching(&Regex::new(&pair[1])?)? }
+            "--expire-vehicle" => {
+                backfill_vehicle_index(cache, version, &pair[1])?;
+                cache.expire_vehicle(&pair[1])?
+            }
+            _ => { continue; }
+        };
+        info!("{} {}: expired {} cache entries", pair[0], pair[1], expired);
+    }
+    Ok(())
+}
+
+/// Gets the whole ship list
+fn ship_list(source: &dyn DataSource) -> Vec<ShipListEntry> {
+    get_ship_list(source).unwrap_or_else(|e| {
+        error!("Couldn't get the ship list: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Keeps only the vehicles picked by `--nation NAME`, `--tier N` and
+/// `--class CLASS`, if given
+fn select_vehicles(args: &[String], list: &[ShipListEntry]) -> Vec<ShipListEntry> {
+    let nations = flag_values(args, "--nation");
+    let tier = flag_value(args, "--tier").map(|tier| {
+        tier.parse().unwrap_or_else(|_| {
+            error!("Bad value for --tier: {}", tier);
+            std::process::exit(1);
+        })
+    });
+    let class = flag_value(args, "--class").map(|class| {
+        ShipClass::from_name(class).unwrap_or_else(|| {
+            error!("Bad value for --class: {}", class);
+            std::process::exit(1);
+        })
+    });
+    list.iter().filter(|entry| {
+        (nations.is_empty() || nations.contains(&entry.nation)) && entry.matches(tier, class)
+    }).cloned().collect()
+}
+
+/// Writes the vehicles to the database, and splits them up into the ship
+/// store
+fn save_vehicles(version: &GameVersion, vehicles: &[Ship]) {
+    let database_path = version.database_path();
+    if let Err(e) = save_database(&database_path, version, vehicles) {
+        error!("Couldn't write {}: {}", database_path.display(), e);
+        std::process::exit(1);
+    }
+    let store_path = version.store_path();
+    if let Err(e) = write_store(&store_path, version, vehicles) {
+        error!("Couldn't write {}: {}", store_path.display(), e);
+        std::process::exit(1);
+    }
+}
+
+/// Loads the ships database, building or updating it first if it's missing,
+/// out of date, or `--update` or `--revalidate` were given
+fn load_vehicles(args: &[String], version: &GameVersion, cache: &Arc<Cache>, source: &Arc<dyn DataSource>, read_only: bool, live: bool) -> Vec<Ship> {
+    let workers = flag_value(args, "--workers").map(|workers| {
+        workers.parse().unwrap_or_else(|_| {
+            error!("Bad value for --workers: {}", workers);
+            std::process::exit(1);
+        })
+    }).unwrap_or(4);
+
+    let database_path = version.database_path();
+    let stored = if database_path.exists() {
+        match load_database(&database_path, version) {
+            Ok(vehicles) => Some(vehicles),
+            Err(e) => {
+                warn!("Couldn't load {}, rebuilding it: {}", database_path.display(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // --update checks the ship list for new and removed vehicles, so the
+    // country pages have to be fresh
+    let update = args.iter().any(|arg| { arg == "--update" });
+    if update {
+        if !live {
+            warn!("Can't refresh the ship list for game version {}, using the cached one", version);
+        } else if read_only {
+            warn!("Can't refresh the ship list without the network, using the cached one");
+        } else {
+            match refresh_ship_list(cache) {
+                Ok(countries) => {
+                    for country in countries {
+                        info!("The ship list for {} has changed", country);
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't refresh the ship list: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // --revalidate asks the site whether anything we've cached has changed,
+    // so those vehicles get re-parsed
+    let mut list = None;
+    let mut changed = vec!();
+    if args.iter().any(|arg| { arg == "--revalidate" }) {
+        if read_only {
+            error!("--revalidate needs the network, it can't be used with --offline or --fixtures");
+            std::process::exit(1);
+        }
+        let all = ship_list(&**source);
+        let ids: Vec<_> = select_vehicles(args, &all).into_iter().map(|entry| { entry.id }).collect();
+        let report = find_changed_vehicles(cache.clone(), version, &ids, workers, |progress| {
+            info!("Revalidated {}/{} vehicles, fetched {} bytes", progress.done, progress.total, progress.bytes_fetched);
+        });
+        report.log();
+        changed = report.changed;
+        list = Some(all);
+    }
+
+    match stored {
+        Some(stored) if !update && changed.is_empty() => {
+            // Databases from before the ship store existed don't have one
+            if !version.store_path().exists() {
+                if let Err(e) = write_store(version.store_path(), version, &stored) {
+                    warn!("Couldn't write {}: {}", version.store_path().display(), e);
+                }
+            }
+            stored
+        }
+        stored => {
+            let list = list.unwrap_or_else(|| { ship_list(&**source) });
+            let selected = select_vehicles(args, &list);
+            let (vehicles, report) = update_database(source.clone(), stored.unwrap_or_default(), &list, &selected, &changed, workers, |progress| {
+                info!("Imported {}/{} vehicles, fetched {} bytes", progress.done, progress.total, progress.bytes_fetched);
+            });
+            report.log();
+            materials::log_unknown_materials();
+            if read_only && report.import.has_missing_entries() {
+                error!("Some cache entries are missing, refusing to build {}", database_path.display());
+                std::process::exit(1);
+            }
+            save_vehicles(version, &vehicles);
+            vehicles
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
     //env_logger::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
-    let vehicles = match std::fs::File::open("ships.dat") {
-        Ok(f) => {
-            bincode::deserialize_from(f).unwrap()
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| { arg == "--offline" }) {
+        download::set_offline(true);
+    }
+    if let Err(e) = configure_downloads(&args) {
+        error!("Bad download options: {}", e);
+        std::process::exit(1);
+    }
+
+    // --armor-materials FILE replaces the built-in armor material table
+    if let Some(path) = flag_value(&args, "--armor-materials") {
+        match materials::MaterialTable::load(path) {
+            Ok(table) => { materials::configure(table); }
+            Err(e) => {
+                error!("Couldn't load the armor material table {}: {}", path, e);
+                std::process::exit(1);
+            }
         }
-        _ => {
-            let ids = get_ship_list();
-            let vehicles: Vec<_> = ids.iter().filter_map(|id| { download_vehicle(&id) }).collect();
+    }
 
-            // Serialize all the found vehicles into a file
-            {
-                //let serialized = bincode::serialize(&vehicles).unwrap();
-                let f = std::fs::File::create("ships.dat").unwrap();
-                //f.write_all(&serialized[..]).unwrap();
-                bincode::serialize_into(f, &vehicles).unwrap();
+    // --fixtures DIR parses a directory of fixed pages instead of the site
+    let fixtures = flag_value(&args, "--fixtures");
+    let read_only = download::is_offline() || fixtures.is_some();
+
+    // Ask the site which version it's serving, and remember it for runs which
+    // can't ask. Without --game-version we stick with the unversioned cache, so
+    // there's no need to ask.
+    let version_flag = flag_value(&args, "--game-version");
+    let live_version = if read_only || version_flag.is_none() {
+        None
+    } else {
+        match GameVersion::detect() {
+            Ok(version) => {
+                if let Err(e) = version.remember() {
+                    warn!("Couldn't record the detected game version: {}", e);
+                }
+                Some(version)
+            }
+            Err(e) => {
+                warn!("Couldn't detect the game version: {}", e);
+                None
             }
-            vehicles
         }
     };
 
-    // Search the vehicles for a name
-    let pensacola: Vec<_> = vehicles.iter().filter(|s| { s.name.contains("Pensacola") }).collect();
-    let mut total_battles = 0;
-    for vehicle in vehicles.iter() {
-        if vehicle.can_battle_with(pensacola[0]) {
-            total_battles += 1;
-            info!("Can battle with {}", vehicle.name);
+    // --game-version picks a version, or "detect" uses the one the site is
+    // serving (or, offline, the one it was last seen serving). Otherwise we
+    // keep using the unversioned cache and database.
+    let version = match version_flag {
+        Some("detect") => {
+            let detected = if read_only { GameVersion::last_detected() } else { live_version.clone() };
+            match detected {
+                Some(version) => version,
+                None => {
+                    error!("Couldn't detect the game version");
+                    std::process::exit(1);
+                }
+            }
         }
+        Some(version) => {
+            match GameVersion::parse(version) {
+                Ok(version) => version,
+                Err(e) => {
+                    error!("Bad --game-version: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => GameVersion::current(),
+    };
+    info!("Using game version {}", version);
+    if version.is_current() && fixtures.is_none() {
+        info!("The data isn't pinned to a game version, so data from different patches may mix in {}; use --game-version detect to pin it", version.cache_dir().display());
     }
-    info!("Pensacola can battle with {} ships", total_battles);
 
-    /*for id in ids {
-        download_vehicle(&id);
-    }*/
-    /*info!("{}", ids[0]);
-    download_vehicle(&ids[0]);*/
-    let mut total_battles = 0;
-    for vehicle_a in vehicles.iter() {
-        for vehicle_b in vehicles.iter() {
-            if vehicle_a.can_battle_with(vehicle_b) {
-                total_battles += 1;
+    // Vehicle pages are only ever the live ones, so they're only fetched for
+    // the version the site is serving
+    let live = version.is_current() || live_version.as_ref() == Some(&version);
+    if !live && !read_only {
+        warn!("The site isn't serving game version {}, so vehicle pages will only be read from its cache", version);
+        if args.iter().any(|arg| { arg == "--revalidate" }) {
+            error!("--revalidate fetches live pages, it can only be used with the version the site is serving");
+            std::process::exit(1);
+        }
+    }
+
+    let cache = Arc::new(Cache::open(version.cache_dir()));
+    if let Err(e) = expire_cache(&args, &cache, &version) {
+        error!("Couldn't expire cache entries: {}", e);
+        std::process::exit(1);
+    }
+
+    // --export-cache FILE and --import-cache FILE move cache bundles between
+    // machines, and then exit
+    if let Some(path) = flag_value(&args, "--export-cache") {
+        let filter = BundleFilter {
+            nations: flag_values(&args, "--nation"),
+            vehicles: flag_values(&args, "--vehicle"),
+        };
+        match export_bundle(&cache, &version, &filter, path) {
+            Ok(count) => { info!("Exported {} cache entries to {}", count, path); }
+            Err(e) => {
+                error!("Couldn't export the cache: {}", e);
+                std::process::exit(1);
             }
         }
+        return;
     }
-    info!("Found {} possible battles", total_battles);
+    if let Some(path) = flag_value(&args, "--import-cache") {
+        if let Err(e) = import_bundle(&cache, &version, path) {
+            error!("Couldn't import {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let source: Arc<dyn DataSource> = match fixtures {
+        Some(dir) => {
+            match FixtureSource::from_dir(dir, version.clone()) {
+                Ok(fixtures) => Arc::new(fixtures),
+                Err(e) => {
+                    error!("Couldn't load fixtures from {}: {}", dir, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None if download::is_offline() => Arc::new(CacheSource::new(cache.clone(), version.clone())),
+        None => Arc::new(HttpSource::new(cache.clone(), version.clone(), live)),
+    };
+
+    // --import-sqlite FILE replaces the database with the ships in an SQLite
+    // export
+    #[cfg(feature = "sqlite")]
+    {
+        if let Some(path) = flag_value(&args, "--import-sqlite") {
+            match sqlite::import_sqlite(path, &version) {
+                Ok(vehicles) => {
+                    info!("Loaded {} vehicles from {}", vehicles.len(), path);
+                    save_vehicles(&version, &vehicles);
+                }
+                Err(e) => {
+                    error!("Couldn't import {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // --import-json FILE replaces the database with the ships in a JSON
+    // export, e.g. after editing it
+    if let Some(path) = flag_value(&args, "--import-json") {
+        match import_json(path) {
+            Ok((json_version, _)) if json_version != version => {
+                error!("{} is for game version {}, not {}", path, json_version, version);
+                std::process::exit(1);
+            }
+            Ok((_, vehicles)) => {
+                info!("Loaded {} vehicles from {}", vehicles.len(), path);
+                save_vehicles(&version, &vehicles);
+            }
+            Err(e) => {
+                error!("Couldn't import {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // --lazy only reads the ship store's index, and loads the ships being
+    // simulated when they're needed. It never loads the whole database, so it
+    // can't update or export it.
+    let lazy = args.iter().any(|arg| { arg == "--lazy" });
+    if lazy {
+        for flag in ["--update", "--revalidate", "--export-sqlite", "--export-json"].iter() {
+            if args.iter().any(|arg| { arg == flag }) {
+                error!("--lazy doesn't load the ships database, so it can't be used with {}", flag);
+                std::process::exit(1);
+            }
+        }
+    }
+    let store = if lazy {
+        match ShipStore::open(version.store_path(), &version) {
+            Ok(store) => {
+                info!("Found {} ships in {}", store.entries().len(), version.store_path().display());
+                Some(store)
+            }
+            Err(e) => {
+                warn!("Couldn't open {}, loading the whole database instead: {}", version.store_path().display(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut vehicles = vec!();
+    if store.is_none() {
+        vehicles = load_vehicles(&args, &version, &cache, &source, read_only, live);
 
-    let dd = download_vehicle("pasd014").unwrap();
-    let bb = download_vehicle("pasb006").unwrap();
+        // --export-sqlite FILE writes the database out to SQLite, and then exits
+        #[cfg(feature = "sqlite")]
+        {
+            if let Some(path) = flag_value(&args, "--export-sqlite") {
+                if let Err(e) = sqlite::export_sqlite(path, &version, &vehicles) {
+                    error!("Couldn't export to {}: {}", path, e);
+                    std::process::exit(1);
+                }
+                info!("Exported {} vehicles to {}", vehicles.len(), path);
+                return;
+            }
+        }
+
+        // --export-json FILE writes the database out as JSON, with the armor
+        // meshes if --with-armor is given, and then exits
+        if let Some(path) = flag_value(&args, "--export-json") {
+            let include_armor = args.iter().any(|arg| { arg == "--with-armor" });
+            if let Err(e) = export_json(path, &version, &vehicles, include_armor) {
+                error!("Couldn't export to {}: {}", path, e);
+                std::process::exit(1);
+            }
+            info!("Exported {} vehicles to {}", vehicles.len(), path);
+            return;
+        }
+
+        // Search the vehicles for a name
+        let pensacola = find_by_name(&vehicles, "Pensacola").or_else(|| {
+            search_by_name(&vehicles, "Pensacola").into_iter().next()
+        });
+        match pensacola {
+            Some(pensacola) => {
+                for config in pensacola.configurations.iter() {
+                    info!("{} can be fitted as {}", pensacola.name, config.label());
+                }
+                let mut total_battles = 0;
+                for vehicle in vehicles.iter() {
+                    if vehicle.can_battle_with(pensacola) {
+                        total_battles += 1;
+                        info!("Can battle with {}", vehicle.name);
+                    }
+                }
+                info!("{} can battle with {} ships", pensacola.name, total_battles);
+            }
+            None => { warn!("Couldn't find Pensacola"); }
+        }
+
+        let mut total_battles = 0;
+        for vehicle_a in vehicles.iter() {
+            for vehicle_b in vehicles.iter() {
+                if vehicle_a.can_battle_with(vehicle_b) {
+                    total_battles += 1;
+                }
+            }
+        }
+        info!("Found {} possible battles", total_battles);
+    }
+
+    // --sqlite FILE loads the ships being simulated from an SQLite export
+    #[cfg(feature = "sqlite")]
+    let sqlite_ships = flag_value(&args, "--sqlite").map(|path| {
+        match sqlite::SqliteShips::open(path, &version) {
+            Ok(database) => database,
+            Err(e) => {
+                error!("Couldn't open {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    // Pick out the two ships we need, from the store if we're being lazy or
+    // from the SQLite export
+    let mut take_ship = |id: &str| -> error::Result<Option<Ship>> {
+        if let Some(store) = &store {
+            return store.get(id);
+        }
+        #[cfg(feature = "sqlite")]
+        {
+            if let Some(database) = &sqlite_ships {
+                return database.get(id);
+            }
+        }
+        // Nothing else needs the full list any more, so take the ship out of it
+        let id = find_by_id(&vehicles, id).map(|ship| { ship.id.clone() });
+        Ok(id.map(|id| {
+            let i = vehicles.iter().position(|ship| { ship.id == id }).unwrap();
+            vehicles.swap_remove(i)
+        }))
+    };
+    // A filtered database may not have them, so fall back on parsing them
+    let mut demo_ship = |id: &str| -> Option<Ship> {
+        let ship = match take_ship(id) {
+            Ok(None) => download_vehicle(&*source, id),
+            ship => ship,
+        };
+        match ship {
+            Ok(Some(ship)) => Some(ship),
+            Ok(None) => {
+                warn!("{} isn't a ship we can simulate, skipping the demo", id);
+                None
+            }
+            Err(e) => {
+                warn!("Couldn't load {}, skipping the demo: {}", id, e);
+                None
+            }
+        }
+    };
+    let (dd, bb) = match (demo_ship("pasd014"), demo_ship("pasb006")) {
+        (Some(dd), Some(bb)) => (dd, bb),
+        _ => { return; }
+    };
     //download_vehicle("pjsb799");
     let x = simulate_attack(&dd.configurations[0].artillery[0].ammo[0], &bb.configurations[0], 10000.0, 30.0, Point3::new(0.0, 0.0, 0.0));
     info!("{:?}", x);
@@ -107,4 +654,25 @@ fn main() {
         info!("{} degrees: {} w/ {} misses/{} penetrations", i as f64 * 10.0, damage, occurrences.get(&ImpactType::Miss).unwrap_or(&0), occurrences.get(&ImpactType::Penetration).unwrap_or(&0));
     }
     info!("Computed 3600 shots in {:?}, {} shots/sec", now.elapsed(), 3600.0 / now.elapsed().as_secs_f64());
+
+    for range in (2..=16).step_by(2) {
+        let range = range as f64 * 1000.0;
+        let damage = damage_per_minute(100, &bb.configurations[0], &bb.configurations[0], range, 90.0, Point3::new(0.0, 0.0, 0.0));
+        info!("At {}m: {} damage per minute, main and secondary batteries", range, damage);
+    }
+
+    let target = &bb.configurations[0];
+    for section in target.hit_points.sections.iter() {
+        info!("{} {:?}: {} HP, {:.0}% of the ship", section.name, section.kind, section.max_hp, 100.0 * target.hit_points.share(section));
+    }
+    match salvos_to_sink(&bb.configurations[0], target, 10000.0, 90.0, 1000) {
+        Some(salvos) => { info!("{} sinks itself in {} salvos at 10km", bb.name, salvos); }
+        None => { info!("{} can't sink itself at 10km", bb.name); }
+    }
+
+    for i in 0..=6 {
+        let azimuth = i as f64 * 30.0;
+        let (damage, floods) = torpedo_estimate(&dd.configurations[0], &bb.configurations[0], 6000.0, 90.0, azimuth);
+        info!("Torpedoes at {} degrees: {} damage and {} floods per spread", azimuth, damage, floods);
+    }
 }
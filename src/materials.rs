@@ -0,0 +1,105 @@
+//! Maps the armor material ids in the armor models to `ArmorType`s. The table
+//! lives in `data/armor_materials.json`, which is built in, and can be swapped
+//! for another file at runtime with `--armor-materials FILE`.
+//!
+//! The built-in table only has the citadel and torpedo belt ids the
+//! classification started out with, so everything else comes out as `Normal`.
+use lazy_static::lazy_static;
+use log::warn;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use crate::error::{Error, Result};
+use crate::gun::ArmorType;
+
+const DEFAULT_MATERIALS: &str = include_str!("../data/armor_materials.json");
+
+/// One run of material ids, `from` to `to` inclusive
+#[derive(Deserialize)]
+struct MaterialRange {
+    from: usize,
+    to: usize,
+    armor_type: ArmorType,
+}
+
+#[derive(Deserialize)]
+pub struct MaterialTable {
+    /// What ids without an entry are taken to be
+    default: ArmorType,
+    materials: Vec<MaterialRange>,
+}
+
+impl MaterialTable {
+    fn parse(json: &str, path: &str) -> Result<MaterialTable> {
+        serde_json::from_str(json).map_err(|e| {
+            Error::Decode { url: path.to_string(), reason: e.to_string() }
+        })
+    }
+
+    /// Loads a table from a JSON file laid out like `data/armor_materials.json`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MaterialTable> {
+        let path = path.as_ref();
+        MaterialTable::parse(&fs::read_to_string(path)?, &path.display().to_string())
+    }
+
+    fn lookup(&self, id: usize) -> Option<&ArmorType> {
+        self.materials.iter().find(|range| { range.from <= id && id <= range.to }).map(|range| { &range.armor_type })
+    }
+}
+
+/// How often a material id without an entry was seen, and the thicknesses it
+/// came with
+#[derive(Default)]
+pub struct UnknownMaterial {
+    pub faces: usize,
+    pub thicknesses: Vec<f64>,
+}
+
+lazy_static! {
+    static ref TABLE: RwLock<MaterialTable> = RwLock::new(
+        MaterialTable::parse(DEFAULT_MATERIALS, "data/armor_materials.json").expect("Built-in armor material table is broken")
+    );
+
+    static ref UNKNOWN: Mutex<BTreeMap<usize, UnknownMaterial>> = Mutex::new(BTreeMap::new());
+}
+
+/// Replaces the table used by `armor_type`
+pub fn configure(table: MaterialTable) {
+    *TABLE.write().unwrap() = table;
+}
+
+/// The armor type of a material id. Ids the table doesn't know are recorded
+/// for `unknown_materials` and get the table's default.
+pub fn armor_type(id: usize, thickness: f64) -> ArmorType {
+    let table = TABLE.read().unwrap();
+    match table.lookup(id) {
+        Some(armor_type) => armor_type.clone(),
+        None => {
+            let mut unknown = UNKNOWN.lock().unwrap();
+            let entry = unknown.entry(id).or_default();
+            entry.faces += 1;
+            if !entry.thicknesses.contains(&thickness) {
+                entry.thicknesses.push(thickness);
+            }
+            table.default.clone()
+        }
+    }
+}
+
+/// Takes the material ids seen so far which the table has no entry for
+pub fn unknown_materials() -> BTreeMap<usize, UnknownMaterial> {
+    std::mem::replace(&mut *UNKNOWN.lock().unwrap(), BTreeMap::new())
+}
+
+/// Logs the material ids the table is missing, so it can be filled in
+pub fn log_unknown_materials() {
+    for (id, material) in unknown_materials() {
+        let mut thicknesses = material.thicknesses;
+        thicknesses.sort_by(|a, b| { a.partial_cmp(b).unwrap() });
+        let thicknesses: Vec<_> = thicknesses.iter().map(|t| { format!("{}mm", t) }).collect();
+        warn!("Armor material {} isn't in the material table ({} faces, {})", id, material.faces, thicknesses.join(", "));
+    }
+}
@@ -1,81 +1,251 @@
-use log::{error, warn, debug, trace};
+use log::{warn, debug, trace, log_enabled, Level};
 use serde_json::Value;
 use serde_json::map::Map;
 
 use crate::ballistics::{Ballistics, Dispersion};
 use crate::gun::*;
-use crate::download::{download, download_with_params};
+use crate::datasource::DataSource;
+use crate::error::{Error, Result};
+use crate::materials;
 
 use serde_derive::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use cgmath::{Matrix4, Point3};
 use std::io::prelude::*;
 use std::convert::TryInto;
 
-fn parse_ballistics(ammo: &Map<String, Value>) -> Ballistics {
-    Ballistics::new(
-        ammo["bulletMass"].as_f64().unwrap(),
-        ammo["bulletDiametr"].as_f64().unwrap(),
-        ammo["bulletSpeed"].as_f64().unwrap(),
-        ammo["bulletAirDrag"].as_f64().unwrap(),
-        ammo["bulletKrupp"].as_f64().unwrap()
+fn missing(path: &str, key: &str) -> Error {
+    Error::MissingField { path: format!("{}.{}", path, key) }
+}
+
+/// Looks up `key` in `obj`, which lives at `path` in the vehicle JSON
+fn get<'a>(obj: &'a Map<String, Value>, path: &str, key: &str) -> Result<&'a Value> {
+    obj.get(key).ok_or_else(|| { missing(path, key) })
+}
+
+fn get_f64(obj: &Map<String, Value>, path: &str, key: &str) -> Result<f64> {
+    get(obj, path, key)?.as_f64().ok_or_else(|| { missing(path, key) })
+}
+
+fn get_str<'a>(obj: &'a Map<String, Value>, path: &str, key: &str) -> Result<&'a str> {
+    get(obj, path, key)?.as_str().ok_or_else(|| { missing(path, key) })
+}
+
+fn get_object<'a>(obj: &'a Map<String, Value>, path: &str, key: &str) -> Result<&'a Map<String, Value>> {
+    get(obj, path, key)?.as_object().ok_or_else(|| { missing(path, key) })
+}
+
+fn get_array<'a>(obj: &'a Map<String, Value>, path: &str, key: &str) -> Result<&'a Vec<Value>> {
+    get(obj, path, key)?.as_array().ok_or_else(|| { missing(path, key) })
+}
+
+fn as_object<'a>(value: &'a Value, path: &str) -> Result<&'a Map<String, Value>> {
+    value.as_object().ok_or_else(|| { Error::MissingField { path: path.to_string() } })
+}
+
+/// Pulls the JSON out of a `var <name> = {...};` line in a page
+fn extract_var(url: &str, page: &str, name: &str) -> Result<Value> {
+    let lines: Vec<_> = page.lines().filter(|line| {
+        line.contains(&format!("var {}", name))
+    }).collect();
+    if lines.len() != 1 {
+        return Err(Error::Decode {
+            url: url.to_string(),
+            reason: format!("Expected to find exactly one {} variable, found {}", name, lines.len()),
+        });
+    }
+    let value = lines[0].split("=").skip(1).collect::<Vec<_>>().join("=");
+    let value = value.trim().trim_end_matches(";");
+    serde_json::from_str(value).map_err(|e| {
+        Error::Decode { url: url.to_string(), reason: e.to_string() }
+    })
+}
+
+fn parse_ballistics(ammo: &Map<String, Value>, path: &str) -> Result<Ballistics> {
+    Ok(Ballistics::new(
+        get_f64(ammo, path, "bulletMass")?,
+        get_f64(ammo, path, "bulletDiametr")?,
+        get_f64(ammo, path, "bulletSpeed")?,
+        get_f64(ammo, path, "bulletAirDrag")?,
+        get_f64(ammo, path, "bulletKrupp")?,
+    ))
+}
+
+/// Ricochet angles the game uses for shells which don't say otherwise
+const DEFAULT_RICOCHET_AT: f64 = 45.0;
+const DEFAULT_ALWAYS_RICOCHET_AT: f64 = 60.0;
+const DEFAULT_NORMALIZATION: f64 = 6.0;
+
+/// Looks up an optional per-shell parameter, logging when we have to fall
+/// back to the default
+fn get_f64_or(obj: &Map<String, Value>, path: &str, key: &str, default: f64) -> f64 {
+    get_f64(obj, path, key).unwrap_or_else(|_| {
+        warn!("{}.{} is missing, using {}", path, key, default);
+        default
+    })
+}
+
+fn parse_ricochet(ammo: &Map<String, Value>, path: &str) -> RicochetAngles {
+    RicochetAngles::new(
+        get_f64_or(ammo, path, "bulletRicochetAt", DEFAULT_RICOCHET_AT),
+        get_f64_or(ammo, path, "bulletAlwaysRicochetAt", DEFAULT_ALWAYS_RICOCHET_AT),
     )
 }
 
-fn parse_ammotype(ammo: &Map<String, Value>) -> Ammo {
-    let ammotype = ammo["ammoType"].as_str().expect("Couldn't find ammoType");
+fn parse_ammotype(ammo: &Map<String, Value>, path: &str) -> Result<Ammo> {
+    let ammotype = get_str(ammo, path, "ammoType")?;
     debug!("Found ammo of type {}", ammotype);
-    let ballistics = parse_ballistics(ammo);
+    let ballistics = parse_ballistics(ammo, path)?;
     if ammotype == "HE" {
-        Ammo::new(
+        Ok(Ammo::new(
             AmmoType::He(HeAmmo::new(
-                ammo["alphaDamage"].as_f64().expect("Couldn't find alphaDamage"),
-                ammo["alphaPiercingHE"].as_f64().expect("Couldn't find alphaPiercingHE"),
+                get_f64(ammo, path, "alphaDamage")?,
+                get_f64(ammo, path, "alphaPiercingHE")?,
             )),
             ballistics,
-        )
+        ))
     } else if ammotype == "AP" {
-        Ammo::new(
+        Ok(Ammo::new(
             AmmoType::Ap(ApAmmo::new(
-                ammo["bulletDiametr"].as_f64().expect("Couldn't find bulletDiametr"),
-                ammo["alphaDamage"].as_f64().expect("Couldn't find alphaDamage"),
-                ammo["bulletDetonator"].as_f64().expect("Couldn't find bulletDetonator"),
-                ammo["bulletDetonatorThreshold"].as_f64().expect("Couldn't find bulletDetonatorThreshold"),
+                get_f64(ammo, path, "bulletDiametr")?,
+                get_f64(ammo, path, "alphaDamage")?,
+                get_f64(ammo, path, "bulletDetonator")?,
+                get_f64(ammo, path, "bulletDetonatorThreshold")?,
+                parse_ricochet(ammo, path),
+                get_f64_or(ammo, path, "bulletCapNormalizeMaxAngle", DEFAULT_NORMALIZATION),
             )),
             ballistics,
-        )
+        ))
     } else if ammotype == "CS" {
-        warn!("Found unimplemented ammo type CS!");
-        Ammo::new(
-            AmmoType::He(HeAmmo::new(1.0, 1.0)), ballistics)
+        Ok(Ammo::new(
+            AmmoType::Sap(SapAmmo::new(
+                get_f64(ammo, path, "bulletDiametr")?,
+                get_f64(ammo, path, "alphaDamage")?,
+                get_f64(ammo, path, "alphaPiercingCS")?,
+                parse_ricochet(ammo, path),
+            )),
+            ballistics,
+        ))
     } else {
-        error!("Found unknown ammo type {}!", ammotype);
-        panic!()
+        Err(Error::UnknownValue { path: format!("{}.ammoType", path), value: ammotype.to_string() })
     }
 }
 
-fn parse_artillery(artillery_spec: &Map<String, Value>) -> Vec<Gun> {
+/// Torpedo ranges are given in units of 30m
+const TORPEDO_RANGE_SCALE: f64 = 30.0;
+
+fn parse_torpedo(ammo: &Map<String, Value>, path: &str) -> Result<TorpedoAmmo> {
+    let ammotype = get_str(ammo, path, "ammoType")?;
+    if ammotype != "torpedo" {
+        return Err(Error::UnknownValue { path: format!("{}.ammoType", path), value: ammotype.to_string() });
+    }
+    Ok(TorpedoAmmo::new(
+        // The game adds a third of the alpha damage on top
+        get_f64(ammo, path, "alphaDamage")? / 3.0 + get_f64(ammo, path, "damage")?,
+        get_f64(ammo, path, "speed")? / 1.944, // knots to m/s, as for ship speed
+        get_f64(ammo, path, "maxDist")? * TORPEDO_RANGE_SCALE,
+        get_f64_or(ammo, path, "uwCritical", 0.0),
+    ))
+}
+
+/// Parses a torpedoes module into its launchers
+fn parse_torpedoes(torpedoes_spec: &Map<String, Value>, path: &str) -> Result<Vec<TorpedoLauncher>> {
+    let launchers = get_object(torpedoes_spec, path, "guns")?;
+    launchers.iter().map(|(key, launcher)| {
+        let path = format!("{}.guns.{}", path, key);
+        let launcher = as_object(launcher, &path)?;
+        let ammo = get_object(launcher, &path, "ammoList")?.iter().map(|(key, ammo)| {
+            let path = format!("{}.ammoList.{}", path, key);
+            parse_torpedo(as_object(ammo, &path)?, &path)
+        }).collect::<Result<Vec<_>>>()?;
+        let arc = match launcher.get("horizSector").and_then(|v| { v.as_array() }) {
+            Some(sector) if sector.len() == 2 && sector.iter().all(|v| { v.is_number() }) => {
+                [sector[0].as_f64().unwrap(), sector[1].as_f64().unwrap()]
+            }
+            _ => {
+                warn!("{}.horizSector is missing, assuming the launcher can fire all around", path);
+                [-180.0, 180.0]
+            }
+        };
+        Ok(TorpedoLauncher::new(
+            ammo,
+            get_f64(launcher, &path, "numBarrels")? as usize,
+            get_f64(launcher, &path, "shotDelay")?,
+            arc,
+        ))
+    }).collect()
+}
+
+/// Dispersion parameters live on each gun, but some data puts them on the
+/// artillery as a whole, so we look in both
+fn parse_dispersion(gun: &Map<String, Value>, gun_path: &str, artillery_spec: &Map<String, Value>, path: &str, max_range: f64, sigma: f64) -> Result<Dispersion> {
+    let lookup = |key: &str| {
+        get_f64(gun, gun_path, key).or_else(|_| { get_f64(artillery_spec, path, key) })
+    };
+    let lookup_or = |key: &str, default: f64| {
+        lookup(key).unwrap_or_else(|_| {
+            warn!("{}.{} is missing, using {}", gun_path, key, default);
+            default
+        })
+    };
+    // The model divides by these, and sampling never ends without a positive
+    // sigma, so reject values which would break it here
+    let check = |key_path: String, value: f64, valid: bool| {
+        if valid {
+            Ok(value)
+        } else {
+            Err(Error::UnknownValue { path: key_path, value: value.to_string() })
+        }
+    };
+    let ideal_distance = lookup("idealDistance")?;
+    let ideal_distance = check(format!("{}.idealDistance", gun_path), ideal_distance, ideal_distance > 0.0)?;
+    let delim = lookup_or("delim", 0.5);
+    let delim = check(format!("{}.delim", gun_path), delim, delim > 0.0 && delim < 1.0)?;
+    let max_range = check(format!("{}.maxDist", path), max_range, max_range > 0.0)?;
+    let sigma = check(format!("{}.sigmaCount", path), sigma, sigma > 0.0)?;
+    Ok(Dispersion::new(
+        lookup("idealRadius")?,
+        lookup("minRadius")?,
+        ideal_distance,
+        lookup_or("taperDist", 0.0),
+        lookup("radiusOnZero")?,
+        lookup("radiusOnDelim")?,
+        lookup("radiusOnMax")?,
+        delim,
+        max_range,
+        sigma,
+    ))
+}
+
+/// Parses an artillery or secondary battery (`atba`) module. `max_range_coef`
+/// is the fire control's bonus to the guns' range.
+fn parse_artillery(artillery_spec: &Map<String, Value>, path: &str, max_range_coef: f64) -> Result<Vec<Gun>> {
     //debug!("{:#?}", artillery_spec);
-    let guns = artillery_spec["guns"].as_object().unwrap();
+    let guns = get_object(artillery_spec, path, "guns")?;
     /*for (key,gun) in guns {
         debug!("{}: {:?}", key, gun);
 }*/
-    let dispersion = Dispersion::new(
-        artillery_spec["minDistH"].as_f64().expect("Couldn't find horizontal"),
-        artillery_spec["minDistV"].as_f64().expect("Couldn't find vertical"),
-        artillery_spec["maxDist"].as_f64().expect("Couldn't find maxrange"),
-        artillery_spec["sigmaCount"].as_f64().expect("Couldn't find sigmaCount")
-    );
-    guns.iter().map(|(_, gun)| {
-        let ammo_list = gun["ammoList"].as_object().expect("Couldn't get ammoList");
+    let max_range = get_f64(artillery_spec, path, "maxDist")? * max_range_coef;
+    let sigma = get_f64(artillery_spec, path, "sigmaCount")?;
+    guns.iter().map(|(key, gun)| {
+        let gun_path = format!("{}.guns.{}", path, key);
+        let gun = as_object(gun, &gun_path)?;
+        let dispersion = parse_dispersion(gun, &gun_path, artillery_spec, path, max_range, sigma)?;
+        let path = gun_path;
+        let ammo_list = get_object(gun, &path, "ammoList")?;
         //debug!("{}: {:#?}", key, gun);
-        let ammo: Vec<_> = ammo_list.iter().map(|(_, ammo)| {
-            parse_ammotype(ammo.as_object().unwrap())
-        }).collect();
-        Gun::new(
+        let ammo = ammo_list.iter().map(|(key, ammo)| {
+            let path = format!("{}.ammoList.{}", path, key);
+            parse_ammotype(as_object(ammo, &path)?, &path)
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(Gun::new(
             dispersion.clone(),
             ammo,
-        )
+            max_range,
+            get_f64(gun, &path, "shotDelay")?,
+            get_f64(gun, &path, "numBarrels")? as usize,
+        ))
     }).collect()
 }
 
@@ -133,7 +303,7 @@ impl RawGeometry {
                         vertices[group.indices[i*3+2]],
                     ],
                     material.thickness as f64,
-                    ArmorType::from_id(material.armor_type),
+                    materials::armor_type(material.armor_type, material.thickness as f64),
                 ));
             }
         }
@@ -141,39 +311,35 @@ impl RawGeometry {
     }
 }
 
-fn parse_armor(url: &str, hull_components: &Map<String, Value>) -> Vec<ArmorFace> {
-    let mut params = Map::new();
-    for (k,v) in hull_components {
-        debug!("Hull has component {}: {}", k, v);
-        params.insert(k.to_string(), v[0].clone());
-    }
-
-    let page = download_with_params(&url, "armor", &Value::Object(params).to_string());
-    let scheme: Vec<_> = page.lines().filter(|line| {
-        line.contains("var scheme")
-    }).collect();
-    if scheme.len() != 1 {
-        error!("Expected to find exactly one scheme variable! Found {}", scheme.len());
-        panic!();
-    }
-    let armor = scheme[0].split("=").skip(1).collect::<Vec<_>>().join("=");
-    let armor: Value = serde_json::from_str(&armor[1..armor.len()-1]).unwrap();
+/// Fetches the armor scheme (which models make up the armor, and where) for
+/// the given choice of modules. `params` is the armor view's params, a JSON
+/// object of component type to module name.
+fn armor_scheme(source: &dyn DataSource, url: &str, params: &str) -> Result<Value> {
+    let page = source.fetch_with_params(&url, "armor", params)?;
+    extract_var(url, &page, "scheme")
+}
 
+/// Fetches the models of an armor scheme and builds the armor mesh
+fn parse_armor(source: &dyn DataSource, armor: &Value) -> Result<Vec<ArmorFace>> {
     let mut faces = vec!();
-    for (_,v) in armor.as_object().unwrap() {
-        let url = format!("https://gamemodels3d.com/games/worldofwarships/data/current/armor/{}", v["model"].as_str().unwrap());
-        let model = download(&url);
+    for (key, v) in as_object(armor, "scheme")? {
+        let path = format!("scheme.{}", key);
+        let v = as_object(v, &path)?;
+        let url = format!("{}/armor/{}", source.version().data_url(), get_str(v, &path, "model")?);
+        let model = source.fetch(&url)?;
         if model.len() == 0 {
             // Sometimes we get 404 for some reason
             continue;
         }
 
         let mut m = [0.0; 16];
-        let transform = v["transform"].as_array().unwrap();
+        let transform = get_array(v, &path, "transform")?;
         for i in 0..4 {
-            let col = transform[i].as_array().unwrap();
+            let col = transform.get(i).and_then(|col| { col.as_array() });
             for j in 0..4 {
-                m[i*4 + j] = col[j].as_f64().expect(&format!("Couldn't get {}th element of column {}", j, i));
+                m[i*4 + j] = col.and_then(|col| { col.get(j) }).and_then(|x| { x.as_f64() }).ok_or_else(|| {
+                    Error::MissingField { path: format!("{}.transform[{}][{}]", path, i, j) }
+                })?;
             }
         }
         let m = Matrix4::new(
@@ -198,7 +364,9 @@ fn parse_armor(url: &str, hull_components: &Map<String, Value>) -> Vec<ArmorFace
             m[3*4 + 3],
         );
         //debug!("Got matrix: {:?}", m);
-        let geometry: RawGeometry = serde_json::from_str(&model).unwrap();
+        let geometry: RawGeometry = serde_json::from_str(&model).map_err(|e| {
+            Error::Decode { url: url.clone(), reason: e.to_string() }
+        })?;
         faces.append(&mut geometry.to_armor_faces(m));
     }
     debug!("Mesh has {} faces", faces.len());
@@ -216,19 +384,20 @@ fn parse_armor(url: &str, hull_components: &Map<String, Value>) -> Vec<ArmorFace
     ];
     debug!("Bounding box: {:?} to {:?}", mins, maxs);
 
-    // Dump the mesh as a .obj to debug
-    {
-        let mut f = std::fs::File::create("test.obj").unwrap();
+    // Dump the mesh as a .obj to debug. Only do this when tracing, since
+    // concurrent imports would all be writing the same file (use --workers 1)
+    if log_enabled!(Level::Trace) {
+        let mut f = std::fs::File::create("test.obj")?;
         for face in faces.iter() {
             for v in face.vertices.iter() {
-                f.write_all(format!("v {} {} {}\n", v.x, v.y, v.z).as_bytes()).unwrap();
+                f.write_all(format!("v {} {} {}\n", v.x, v.y, v.z).as_bytes())?;
             }
         }
         for i in 0..faces.len() {
-            f.write_all(format!("f {} {} {}\n", i*3+1, i*3+2, i*3+3).as_bytes()).unwrap();
+            f.write_all(format!("f {} {} {}\n", i*3+1, i*3+2, i*3+3).as_bytes())?;
         }
     }
-    faces
+    Ok(faces)
 }
 
 fn find_size(faces: &Vec<ArmorFace>) -> [f64; 3] {
@@ -249,89 +418,467 @@ fn find_size(faces: &Vec<ArmorFace>) -> [f64; 3] {
     ]
 }
 
-fn parse_hull(url: &str, ship_spec: &Value, components: &Map<String, Value>) -> ShipConfiguration {
-    let hull_spec = ship_spec["components"].as_object().unwrap();
+/// Collects the hit-location sections among the values of `obj`, which are the
+/// objects with an `hlType`
+fn parse_hit_locations(obj: &Map<String, Value>, path: &str) -> Result<Vec<HitLocation>> {
+    obj.iter().filter_map(|(key, value)| {
+        let section = value.as_object()?;
+        let kind = section.get("hlType")?.as_str()?;
+        Some((key, section, kind))
+    }).map(|(key, section, kind)| {
+        let path = format!("{}.{}", path, key);
+        Ok(HitLocation::new(key.to_string(), HitLocationType::from_name(kind), get_f64(section, &path, "maxHP")?))
+    }).collect()
+}
 
-    for (key, value) in hull_spec {
-        debug!("Found component {}: {}", key, value);
+/// The hit points of a hull, plus the turret sections of the artillery
+/// mounted on it
+fn parse_hit_points(hull: &Map<String, Value>, hull_path: &str, artillery: Option<(&Map<String, Value>, &str)>) -> Result<HitPoints> {
+    let mut sections = parse_hit_locations(hull, hull_path)?;
+    if let Some((artillery_spec, path)) = artillery {
+        for (key, gun) in get_object(artillery_spec, path, "guns")? {
+            let gun_path = format!("{}.guns.{}", path, key);
+            for mut section in parse_hit_locations(as_object(gun, &gun_path)?, &gun_path)? {
+                // Every turret's section has the same key, so name it after the turret
+                section.name = key.to_string();
+                sections.push(section);
+            }
+        }
+    }
+    Ok(HitPoints::new(get_f64(hull, hull_path, "health")?, sections))
+}
+
+/// The fraction of a torpedo's damage the hull's torpedo protection absorbs.
+/// The first of the game's `floodNodes` starts with the share of a torpedo's
+/// damage the hull takes, which is a third with no protection at all. Hulls
+/// without `floodNodes` get no protection.
+fn parse_torpedo_protection(hull: &Map<String, Value>, hull_path: &str) -> Result<f64> {
+    if !hull.contains_key("floodNodes") {
+        debug!("{} has no floodNodes, so no torpedo protection", hull_path);
+        return Ok(0.0);
+    }
+    let coefficient = get_array(hull, hull_path, "floodNodes")?.get(0).and_then(|node| { node.as_array()?.get(0)?.as_f64() }).ok_or_else(|| {
+        missing(hull_path, "floodNodes[0][0]")
+    })?;
+    if coefficient <= 0.0 || coefficient > 1.0 {
+        return Err(Error::UnknownValue { path: format!("{}.floodNodes[0][0]", hull_path), value: coefficient.to_string() });
+    }
+    // Unprotected hulls round a third up
+    Ok((1.0 - coefficient * 3.0).max(0.0))
+}
+
+/// The module names a hull offers for one type of component, e.g. its
+/// artillery options. `[None]` if it has none, so that it still takes part in
+/// the combinations.
+fn module_choices<'a>(hull_spec: &'a Map<String, Value>, spec_path: &str, component_type: &str) -> Result<Vec<Option<&'a str>>> {
+    if !hull_spec.contains_key(component_type) {
+        return Ok(vec![None]);
     }
+    let modules = get_array(hull_spec, spec_path, component_type)?.iter().enumerate().map(|(i, module)| {
+        module.as_str().map(Some).ok_or_else(|| { missing(spec_path, &format!("{}[{}]", component_type, i)) })
+    }).collect::<Result<Vec<_>>>()?;
+    if modules.is_empty() {
+        Ok(vec![None])
+    } else {
+        Ok(modules)
+    }
+}
+
+/// Every choice of modules a hull can be fitted with, as component type to
+/// module name. The `VARYING_COMPONENTS` take every option; other components
+/// only ever have one option that matters to us, so they take the first.
+fn hull_configurations(hull_spec: &Map<String, Value>, spec_path: &str) -> Result<Vec<BTreeMap<String, String>>> {
+    let mut configurations = vec![BTreeMap::new()];
+    for (component_type, options) in hull_spec {
+        let choices = if VARYING_COMPONENTS.contains(&component_type.as_str()) {
+            module_choices(hull_spec, spec_path, component_type)?
+        } else {
+            vec![options.get(0).and_then(|v| { v.as_str() })]
+        };
+        configurations = configurations.into_iter().flat_map(|modules| {
+            choices.iter().map(move |module| {
+                let mut modules = modules.clone();
+                if let Some(module) = module {
+                    modules.insert(component_type.to_string(), module.to_string());
+                }
+                modules
+            })
+        }).collect();
+    }
+    Ok(configurations)
+}
+
+/// The component types which change a ship's armor: the hull, and the
+/// turrets that come with the artillery
+const ARMOR_COMPONENTS: [&str; 2] = ["hull", "artillery"];
 
-    let hull = &components[hull_spec["hull"].as_array().unwrap()[0].as_str().unwrap()];
-    let max_speed = hull["maxSpeed"].as_f64().unwrap() / 1.944; // Scaling factor to get m/s, as far as I can tell
+/// The armor view params for a choice of modules. Only the
+/// `ARMOR_COMPONENTS` are passed, so that configurations which only differ
+/// in other modules share one armor view.
+fn armor_params(url: &str, modules: &BTreeMap<String, String>) -> Result<String> {
+    let modules: BTreeMap<_, _> = modules.iter().filter(|(component_type, _)| {
+        ARMOR_COMPONENTS.contains(&component_type.as_str())
+    }).collect();
+    serde_json::to_string(&modules).map_err(|e| {
+        Error::Decode { url: url.to_string(), reason: e.to_string() }
+    })
+}
 
-    let name = hull["name"].as_str().unwrap();
+pub fn vehicle_url(vehicle_id: &str) -> String {
+    format!("https://gamemodels3d.com/games/worldofwarships/vehicles/{}", vehicle_id)
+}
 
-    let artillery = if hull_spec.contains_key("artillery") {
-        let artillery = &hull_spec["artillery"];
-        let artillery = artillery.as_array().unwrap();
-        if artillery.len() != 1 {
-            warn!("Found an artillery of length {}!", artillery.len());
+/// The params of every armor view `download_vehicle` would fetch for a
+/// vehicle page, so that they can be found in the cache without parsing.
+pub fn armor_view_params(url: &str, page: &str) -> Result<Vec<String>> {
+    let v = extract_var(url, page, "_vehicle")?;
+    let v = as_object(&v, "_vehicle")?;
+    let hulls = get_object(get_object(v, "_vehicle", "ShipUpgradeInfo")?, "ShipUpgradeInfo", "_Hull")?;
+    let mut params = vec!();
+    for (key, hull) in hulls {
+        let path = format!("ShipUpgradeInfo._Hull.{}", key);
+        let spec_path = format!("{}.components", path);
+        let hull_spec = get_object(as_object(hull, &path)?, &path, "components")?;
+        for modules in hull_configurations(hull_spec, &spec_path)? {
+            let modules = armor_params(url, &modules)?;
+            if !params.contains(&modules) {
+                params.push(modules);
+            }
         }
-        let artillery = &artillery[0];
-        debug!("Parsing artillery: {:?}", artillery);
-        parse_artillery(components[artillery.as_str().unwrap()].as_object().unwrap())
-    } else {
-        vec!()
-    };
-    let geometry = parse_armor(url, hull_spec);
+    }
+    Ok(params)
+}
 
-    let size = find_size(&geometry);
-    let length = size[2] * 1.53; // Scaling factor to get meters
+/// Parses a hull into one configuration for every choice of modules from
+/// `hull_configurations`. Many combinations have the same armor, so each
+/// armor view is only fetched once, and each mesh is only parsed once and
+/// kept in `armor` along with its scheme.
+fn parse_hull(source: &dyn DataSource, url: &str, ship_spec: &Value, components: &Map<String, Value>, path: &str, armor: &mut Vec<(String, Arc<Vec<ArmorFace>>)>) -> Result<Vec<ShipConfiguration>> {
+    let hull_spec = get_object(as_object(ship_spec, path)?, path, "components")?;
+    let spec_path = format!("{}.components", path);
 
-    ShipConfiguration::new(
-        artillery,
-        geometry,
-        max_speed,
-        length,
-        name.to_string(),
-    )
+    for (key, value) in hull_spec {
+        debug!("Found component {}: {}", key, value);
+    }
+
+    let hull_name = get_array(hull_spec, &spec_path, "hull")?.get(0).and_then(|v| { v.as_str() }).ok_or_else(|| {
+        missing(&spec_path, "hull[0]")
+    })?;
+    let hull_path = format!("Components.{}", hull_name);
+    let hull = get_object(components, "Components", hull_name)?;
+    let max_speed = get_f64(hull, &hull_path, "maxSpeed")? / 1.944; // Scaling factor to get m/s, as far as I can tell
+
+    let name = get_str(hull, &hull_path, "name")?;
+    let torpedo_protection = parse_torpedo_protection(hull, &hull_path)?;
+
+    let mut configs = vec!();
+    // Armor view params to the index of their mesh in `armor`
+    let mut views: HashMap<String, usize> = HashMap::new();
+    for modules in hull_configurations(hull_spec, &spec_path)? {
+        let max_range_coef = match modules.get("fireControl") {
+            Some(fire_control) => {
+                let fc_path = format!("Components.{}", fire_control);
+                get_f64(get_object(components, "Components", fire_control)?, &fc_path, "maxDistCoef").unwrap_or(1.0)
+            }
+            None => 1.0,
+        };
+        let artillery = match modules.get("artillery") {
+            Some(artillery) => Some((get_object(components, "Components", artillery)?, format!("Components.{}", artillery))),
+            None => None,
+        };
+        let guns = match &artillery {
+            Some((artillery_spec, path)) => {
+                debug!("Parsing artillery: {:?}", path);
+                parse_artillery(artillery_spec, path, max_range_coef)?
+            }
+            None => vec!(),
+        };
+        let hit_points = parse_hit_points(hull, &hull_path, artillery.as_ref().map(|(spec, path)| { (*spec, path.as_str()) }))?;
+        let secondaries = match modules.get("atba") {
+            Some(atba) => {
+                debug!("Parsing secondaries: {:?}", atba);
+                parse_artillery(get_object(components, "Components", atba)?, &format!("Components.{}", atba), 1.0)?
+            }
+            None => vec!(),
+        };
+        let torpedoes = match modules.get("torpedoes") {
+            Some(torpedoes) => {
+                debug!("Parsing torpedoes: {:?}", torpedoes);
+                parse_torpedoes(get_object(components, "Components", torpedoes)?, &format!("Components.{}", torpedoes))?
+            }
+            None => vec!(),
+        };
+
+        let params = armor_params(url, &modules)?;
+        let index = match views.get(&params) {
+            Some(index) => *index,
+            None => {
+                let scheme = armor_scheme(source, url, &params)?;
+                let key = scheme.to_string();
+                let index = match armor.iter().position(|(armor_key, _)| { *armor_key == key }) {
+                    Some(index) => index,
+                    None => {
+                        let geometry = parse_armor(source, &scheme)?;
+                        armor.push((key, Arc::new(geometry)));
+                        armor.len() - 1
+                    }
+                };
+                views.insert(params, index);
+                index
+            }
+        };
+        let geometry = armor[index].1.clone();
+
+        let size = find_size(&geometry);
+        let length = size[2] * 1.53; // Scaling factor to get meters
+
+        configs.push(ShipConfiguration::new(
+            guns,
+            secondaries,
+            torpedoes,
+            index,
+            geometry,
+            max_speed,
+            length,
+            name.to_string(),
+            modules,
+            hit_points,
+            torpedo_protection,
+        ));
+    }
+    Ok(configs)
 }
 
-pub fn download_vehicle(vehicle_id: &str) -> Option<Ship> {
+/// Downloads and parses the given vehicle. Returns `None` for vehicles we
+/// don't simulate (auxiliaries and submarines).
+pub fn download_vehicle(source: &dyn DataSource, vehicle_id: &str) -> Result<Option<Ship>> {
     trace!("Downloading vehicle_id={}", vehicle_id);
-    let url = format!("https://gamemodels3d.com/games/worldofwarships/vehicles/{}", vehicle_id);
-    let page = download(&url);
+    let url = vehicle_url(vehicle_id);
+    let page = source.fetch(&url)?;
 
-    let vehicle: Vec<_> = page.lines().filter(|line| {
-        line.contains("var _vehicle")
-    }).collect();
-    if vehicle.len() != 1 {
-        panic!("Expected vehicle length to be 1!");
-    }
-    let spec = vehicle[0].split("=").skip(1).collect::<Vec<_>>().join("=");
+    let v = extract_var(&url, &page, "_vehicle")?;
     //println!("Spec: {}", spec);
-    let v: Value = serde_json::from_str(&spec[1..spec.len()-1]).unwrap();
-    let vehicle_components = v["Components"].as_object().unwrap();
-    let hulls = v["ShipUpgradeInfo"]["_Hull"].as_object().unwrap();
+    let v = as_object(&v, "_vehicle")?;
+
+    let name = get_str(v, "_vehicle", "name")?;
+    let class = get_str(v, "_vehicle", "class")?;
+    let class = match ShipClass::from_name(class) {
+        Some(class) => class,
+        None if class == "auxiliary" || class == "submarine" => {
+            // Ignore these
+            return Ok(None);
+        }
+        None => {
+            return Err(Error::UnknownValue { path: format!("{}.class", vehicle_id), value: class.to_string() });
+        }
+    };
+
+    let vehicle_components = get_object(v, "_vehicle", "Components")?;
+    let hulls = get_object(get_object(v, "_vehicle", "ShipUpgradeInfo")?, "ShipUpgradeInfo", "_Hull")?;
     let mut configs = vec!();
+    let mut armor = vec!();
     for (key, value) in hulls {
         debug!("Found hull {}", key);
-        let hull = parse_hull(&url, value, &vehicle_components);
-        configs.push(hull);
+        let mut hull = parse_hull(source, &url, value, &vehicle_components, &format!("ShipUpgradeInfo._Hull.{}", key), &mut armor)?;
+        configs.append(&mut hull);
     }
 
-    let name = v["name"].as_str().unwrap();
-    let class = v["class"].as_str().unwrap();
-    let class = if class == "destroyer" {
-        ShipClass::Destroyer
-    } else if class == "cruiser" {
-        ShipClass::Cruiser
-    } else if class == "battleship" {
-        ShipClass::Battleship
-    } else if class == "aircarrier" {
-        ShipClass::AircraftCarrier
-    } else if class == "auxiliary" || class == "submarine" {
-        // Ignore these
-        return None;
-    } else {
-        error!("Found unknown ship class {} for {}", class, name);
-        panic!();
-    };
-
-    Some(Ship::new(
+    let level = get(v, "_vehicle", "level")?.as_u64().ok_or_else(|| { missing("_vehicle", "level") })?;
+    Ok(Some(Ship::new(
+        vehicle_id.to_string(),
         configs,
-        v["level"].as_i64().unwrap().try_into().unwrap(),
-        v["name"].as_str().unwrap().to_string(),
+        level.try_into().map_err(|_| {
+            Error::UnknownValue { path: "_vehicle.level".to_string(), value: level.to_string() }
+        })?,
+        name.to_string(),
         class,
-    ))
+        armor.into_iter().map(|(_, geometry)| { geometry }).collect(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fixtures, plate};
+    use cgmath::Vector3;
+
+    #[test]
+    fn parses_fixture_vehicle() {
+        let ship = download_vehicle(&fixtures(), "pasd014").unwrap().unwrap();
+        assert_eq!(ship.id, "pasd014");
+        assert_eq!(ship.name, "Test Destroyer");
+        assert_eq!(ship.tier, 8);
+        assert_eq!(ship.class, ShipClass::Destroyer);
+
+        // One configuration per artillery, fire control and torpedoes
+        assert_eq!(ship.configurations.len(), 8);
+        let config = &ship.configurations[0];
+        assert_eq!(config.name, "PASD014_Test_Destroyer");
+        assert_eq!(config.modules["fireControl"], "A_FireControl");
+        assert!((config.speed - 70.0 / 1.944).abs() < 1e-9);
+        assert!((config.torpedo_protection - 0.4).abs() < 1e-9);
+
+        assert_eq!(config.artillery.len(), 1);
+        let gun = &config.artillery[0];
+        assert_eq!(gun.reload, 3.0);
+        assert_eq!(gun.range, 12000.0);
+        assert_eq!(gun.barrels, 2);
+        assert_eq!(ship.configurations[2].modules["fireControl"], "B_FireControl");
+        assert!((ship.configurations[2].artillery[0].range - 13200.0).abs() < 1e-6);
+        match &gun.ammo[0].bullet {
+            AmmoType::He(he) => {
+                assert_eq!(he.damage, 1800.0);
+                assert_eq!(he.piercing, 21.0);
+            }
+            _ => panic!("Expected HE"),
+        }
+
+        assert_eq!(config.torpedoes.len(), 1);
+        let launcher = &config.torpedoes[0];
+        assert_eq!(launcher.barrels, 5);
+        assert_eq!(launcher.arc, [30.0, 150.0]);
+        assert_eq!(launcher.ammo[0].damage, 12000.0);
+        assert_eq!(launcher.ammo[0].range, 10500.0);
+        assert!(launcher.can_fire_at(90.0) && !launcher.can_fire_at(0.0));
+
+        // B_Torpedoes doesn't say where it can fire, so it can fire anywhere
+        let config = &ship.configurations[1];
+        assert_eq!(config.modules["torpedoes"], "B_Torpedoes");
+        assert_eq!(config.torpedoes[0].barrels, 3);
+        assert!([0.0, 90.0, 180.0, -180.0, 270.0].iter().all(|bearing| { config.torpedoes[0].can_fire_at(*bearing) }));
+        let config = &ship.configurations[0];
+
+        assert_eq!(config.hit_points.health, 16000.0);
+        assert_eq!(config.hit_points.sections.len(), 2);
+
+        assert_eq!(config.geometry.len(), 3);
+        assert_eq!(config.geometry.iter().filter(|face| { face.armor_type == ArmorType::Citadel }).count(), 2);
+        assert!((config.length - 100.0 * 1.53).abs() < 1e-9);
+
+        // The armor only depends on the hull and artillery, so there's one
+        // armor view (and mesh) per artillery, shared by the configurations
+        // which differ in other modules. B_Artillery's adds a turret.
+        let page = fixtures().fetch(&vehicle_url("pasd014")).unwrap();
+        assert_eq!(armor_view_params(&vehicle_url("pasd014"), &page).unwrap(), vec![
+            r#"{"artillery":"A_Artillery","hull":"A_Hull"}"#.to_string(),
+            r#"{"artillery":"B_Artillery","hull":"A_Hull"}"#.to_string(),
+        ]);
+        assert_eq!(ship.armor.len(), 2);
+        for config in ship.configurations.iter() {
+            let index = if config.modules["artillery"] == "A_Artillery" { 0 } else { 1 };
+            assert_eq!(config.armor, index);
+            assert!(Arc::ptr_eq(&config.geometry, &ship.armor[index]));
+        }
+        let config = &ship.configurations[4];
+        assert_eq!(config.modules["artillery"], "B_Artillery");
+        assert_eq!(config.artillery[0].range, 11000.0);
+        assert_eq!(config.artillery[0].reload, 4.0);
+        assert_eq!(config.geometry.len(), 4);
+    }
+
+    #[test]
+    fn parses_and_simulates_sap() {
+        let ammo = serde_json::json!({
+            "ammoType": "CS",
+            "bulletDiametr": 0.152,
+            "alphaDamage": 3000.0,
+            "alphaPiercingCS": 30.0,
+            "bulletRicochetAt": 60.0,
+            "bulletAlwaysRicochetAt": 75.0,
+            "bulletMass": 55.0,
+            "bulletSpeed": 950.0,
+            "bulletAirDrag": 0.3,
+            "bulletKrupp": 2400.0,
+        });
+        let ammo = parse_ammotype(ammo.as_object().unwrap(), "ammo").unwrap();
+        let sap = match &ammo.bullet {
+            AmmoType::Sap(sap) => sap,
+            _ => panic!("Expected SAP"),
+        };
+        assert_eq!(sap.diameter, 0.152);
+        assert_eq!(sap.damage, 3000.0);
+        assert_eq!(sap.piercing, 30.0);
+        assert_eq!(sap.ricochet.ricochet_at, 60.0);
+        assert_eq!(sap.ricochet.always_ricochet_at, 75.0);
+
+        // Head on. SAP has no fuse, so what it gets through takes the damage,
+        // and the shell's own penetration doesn't matter.
+        let head_on = Vector3::new(0.0, 0.0, 1.0);
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let hit = |thickness, armor_type| { sap.compute_damage(&plate(thickness, armor_type), 0.0, 0.0, head_on, origin) };
+        assert_eq!(hit(25.0, ArmorType::Citadel), (3000.0, ImpactType::Citadel));
+        assert_eq!(hit(25.0, ArmorType::Normal), (1000.0, ImpactType::Penetration));
+        assert_eq!(hit(25.0, ArmorType::TorpedoProtectionBelt), (0.0, ImpactType::TorpedoProtection));
+        assert_eq!(hit(31.0, ArmorType::Citadel), (0.0, ImpactType::NonPenetration));
+
+        // 80 degrees from the plate's normal always ricochets, unless the
+        // shell overmatches the plate
+        let angled = Vector3::new(80.0f64.to_radians().sin(), 0.0, 80.0f64.to_radians().cos());
+        assert_eq!(sap.compute_damage(&plate(25.0, ArmorType::Normal), 0.0, 0.0, angled, origin), (0.0, ImpactType::Ricochet));
+        assert_eq!(sap.compute_damage(&plate(10.0, ArmorType::Normal), 0.0, 0.0, angled, origin), (1000.0, ImpactType::Penetration));
+        // 50 degrees never does
+        let angled = Vector3::new(50.0f64.to_radians().sin(), 0.0, 50.0f64.to_radians().cos());
+        assert_eq!(sap.compute_damage(&plate(25.0, ArmorType::Normal), 0.0, 0.0, angled, origin), (1000.0, ImpactType::Penetration));
+    }
+
+    #[test]
+    fn varies_secondaries_between_configurations() {
+        let hull_spec = serde_json::json!({
+            "hull": ["A_Hull"],
+            "artillery": ["A_Artillery"],
+            "atba": ["A_ATBA", "B_ATBA"],
+            "engine": ["A_Engine", "B_Engine"],
+        });
+        let configurations = hull_configurations(hull_spec.as_object().unwrap(), "components").unwrap();
+        let atba: Vec<_> = configurations.iter().map(|modules| { modules["atba"].as_str() }).collect();
+        assert_eq!(atba, vec!["A_ATBA", "B_ATBA"]);
+        // Engines don't change anything we simulate, so only the first counts
+        assert!(configurations.iter().all(|modules| { modules["engine"] == "A_Engine" }));
+    }
+
+    #[test]
+    fn rejects_dispersion_which_would_break_the_model() {
+        let gun = serde_json::json!({
+            "idealRadius": 10.0, "minRadius": 2.8, "idealDistance": 1000.0,
+            "radiusOnZero": 0.2, "radiusOnDelim": 0.5, "radiusOnMax": 0.6, "delim": 0.5,
+        });
+        let spec = serde_json::Map::new();
+        let parse = |gun: &Value, max_range, sigma| {
+            parse_dispersion(gun.as_object().unwrap(), "gun", &spec, "artillery", max_range, sigma)
+        };
+        assert!(parse(&gun, 20000.0, 2.0).is_ok());
+        assert!(parse(&gun, 20000.0, 0.0).is_err());
+        assert!(parse(&gun, 20000.0, -1.0).is_err());
+        assert!(parse(&gun, 0.0, 2.0).is_err());
+        for delim in [0.0, 1.0, 1.5].iter() {
+            let mut gun = gun.clone();
+            gun["delim"] = serde_json::json!(delim);
+            match parse(&gun, 20000.0, 2.0) {
+                Err(Error::UnknownValue { path, .. }) => { assert_eq!(path, "gun.delim"); }
+                _ => panic!("Expected delim {} to be rejected", delim),
+            }
+        }
+        let mut gun = gun.clone();
+        gun["idealDistance"] = serde_json::json!(0.0);
+        assert!(parse(&gun, 20000.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn parses_torpedo_protection() {
+        let parse = |hull: Value| { parse_torpedo_protection(hull.as_object().unwrap(), "hull") };
+        assert!((parse(serde_json::json!({"floodNodes": [[0.2, 0.5, 40.0]]})).unwrap() - 0.4).abs() < 1e-9);
+        // The game writes a third as 0.333, and a little over shouldn't count as protection either
+        assert_eq!(parse(serde_json::json!({"floodNodes": [[0.3334, 0.5, 40.0]]})).unwrap(), 0.0);
+        assert_eq!(parse(serde_json::json!({})).unwrap(), 0.0);
+        assert!(parse(serde_json::json!({"floodNodes": [[0.0, 0.5, 40.0]]})).is_err());
+        assert!(parse(serde_json::json!({"floodNodes": []})).is_err());
+    }
+
+    #[test]
+    fn missing_fixture_is_an_error() {
+        match download_vehicle(&fixtures(), "pasd999") {
+            Err(Error::NotCached { .. }) => {}
+            _ => panic!("Expected NotCached"),
+        }
+    }
 }
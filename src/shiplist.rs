@@ -1,49 +1,228 @@
-use scraper::{Html, Selector};
+use scraper::{Html, Selector, ElementRef};
 use regex::Regex;
 use log::{info};
 
-use crate::download::download;
+use crate::datasource::DataSource;
+use crate::error::Result;
+use crate::gun::ShipClass;
 
-fn get_country_ships(country: &str) -> Vec<String> {
-    let url = format!("https://gamemodels3d.com/games/worldofwarships/vehicles/{}", country);
-    let page = download(&url);
+pub const COUNTRIES: [&str; 11] = [
+    "japan",
+    "usa",
+    "germany",
+    "ussr",
+    "uk",
+    "panasia",
+    "france",
+    "commonwealth",
+    "italy",
+    "pan_america",
+    "europe",
+];
 
-    let document = Html::parse_document(&page);
+pub fn country_url(country: &str) -> String {
+    format!("https://gamemodels3d.com/games/worldofwarships/vehicles/{}", country)
+}
+
+/// A vehicle as it appears on a country's vehicle list. Only the id and nation
+/// are always known, the rest is whatever the listing happens to show.
+#[derive(Clone, Debug)]
+pub struct ShipListEntry {
+    pub id: String,
+    pub nation: String,
+    pub name: Option<String>,
+    pub tier: Option<usize>,
+    pub class: Option<ShipClass>,
+}
+
+impl ShipListEntry {
+    /// Fills in anything we don't know from another listing of the same vehicle
+    fn merge(&mut self, other: ShipListEntry) {
+        if self.name.is_none() {
+            self.name = other.name;
+        }
+        if self.tier.is_none() {
+            self.tier = other.tier;
+        }
+        if self.class.is_none() {
+            self.class = other.class;
+        }
+    }
+
+    /// Returns true if the entry is known to match the given tier and class.
+    /// An entry whose tier or class we don't know doesn't match a filter on it.
+    pub fn matches(&self, tier: Option<usize>, class: Option<ShipClass>) -> bool {
+        tier.map_or(true, |tier| { self.tier == Some(tier) })
+            && class.map_or(true, |class| { self.class == Some(class) })
+    }
+}
+
+fn parse_roman(numeral: &str) -> Option<usize> {
+    let tiers = ["I", "II", "III", "IV", "V", "VI", "VII", "VIII", "IX", "X", "XI"];
+    tiers.iter().position(|tier| { *tier == numeral }).map(|i| { i + 1 })
+}
+
+/// Parses a tier written either as a number or as a roman numeral
+fn parse_tier(tier: &str) -> Option<usize> {
+    let tier = tier.trim();
+    tier.parse().ok().or_else(|| { parse_roman(tier) })
+}
+
+/// Vehicle ids encode the class in their fourth letter, e.g. the "D" in
+/// "PASD014"
+fn class_from_id(id: &str) -> Option<ShipClass> {
+    id.chars().nth(3).and_then(|c| {
+        match c.to_ascii_uppercase() {
+            'D' => Some(ShipClass::Destroyer),
+            'C' => Some(ShipClass::Cruiser),
+            'B' => Some(ShipClass::Battleship),
+            'A' => Some(ShipClass::AircraftCarrier),
+            _ => None,
+        }
+    })
+}
+
+/// Looks for the tier and class in the link's data attributes, then in its
+/// text (e.g. "VIII Pensacola"). The class falls back on the vehicle id.
+/// `fixtures/vehicles_usa.html` has the layouts this handles; it's written by
+/// hand, so check it against a real listing if the results look off.
+fn parse_entry(element: &ElementRef, id: &str, nation: &str) -> ShipListEntry {
+    let attr = |names: &[&str]| {
+        names.iter().filter_map(|name| { element.value().attr(name) }).next()
+    };
+    let text: String = element.text().collect::<Vec<_>>().join(" ");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut name = attr(&["data-name", "title"]).map(|name| { name.trim().to_string() });
+    let mut tier = attr(&["data-tier", "data-level"]).and_then(parse_tier);
+    if !text.is_empty() {
+        let re = Regex::new(r"^(?:(?:Tier\s+)?([IVX]+|\d+)\s+)?(.+)$").unwrap();
+        if let Some(capture) = re.captures(&text) {
+            if tier.is_none() {
+                tier = capture.get(1).and_then(|numeral| { parse_tier(numeral.as_str()) });
+            }
+            if name.is_none() {
+                name = Some(capture[2].to_string());
+            }
+        }
+    }
+    let class = attr(&["data-class", "data-type"]).and_then(ShipClass::from_name).or_else(|| { class_from_id(id) });
+
+    ShipListEntry {
+        id: id.to_string(),
+        nation: nation.to_string(),
+        name: name.filter(|name| { !name.is_empty() }),
+        tier: tier,
+        class: class,
+    }
+}
+
+/// Pulls the vehicles out of a country's vehicle list page. A vehicle linked
+/// more than once only appears once, in the order it was first seen.
+pub fn parse_country_page(page: &str, nation: &str) -> Vec<ShipListEntry> {
+    let document = Html::parse_document(page);
     let a_selector = Selector::parse("a").unwrap();
-    let mut ids = vec!();
+    let re = Regex::new(r"/games/worldofwarships/vehicles/(\w+\d+)").unwrap();
+    let mut entries = vec!();
     for element in document.select(&a_selector) {
         let href = element.value().attr("href");
-        let re = Regex::new(r"/games/worldofwarships/vehicles/(\w+\d+)").unwrap();
         if let Some(href) = href {
             for capture in re.captures_iter(href) {
-                ids.push(capture[1].to_string());
+                add_entry(&mut entries, parse_entry(&element, &capture[1], nation));
             }
         }
     }
-    info!("Found {} ships for country {}", ids.len(), country);
-    ids
+    entries
+}
+
+/// Adds an entry, or merges it into the one already there for its vehicle
+fn add_entry(entries: &mut Vec<ShipListEntry>, entry: ShipListEntry) {
+    match entries.iter_mut().find(|existing| { existing.id == entry.id }) {
+        Some(existing) => { existing.merge(entry); }
+        None => { entries.push(entry); }
+    }
 }
 
-pub fn get_ship_list() -> Vec<String> {
-    let countries = [
-        "japan",
-        "usa",
-        "germany",
-        "ussr",
-        "uk",
-        "panasia",
-        "france",
-        "commonwealth",
-        "italy",
-        "pan_america",
-        "europe",
-    ];
+fn get_country_ships(source: &dyn DataSource, country: &str) -> Result<Vec<ShipListEntry>> {
+    let page = source.fetch(&country_url(country))?;
+    let entries = parse_country_page(&page, country);
+    info!("Found {} ships for country {}", entries.len(), country);
+    Ok(entries)
+}
 
+/// Lists the vehicles of every country. A vehicle listed by more than one
+/// country keeps the nation it was first listed under.
+pub fn get_ship_list(source: &dyn DataSource) -> Result<Vec<ShipListEntry>> {
     let mut ships = vec!();
-    for country in countries.iter() {
+    for country in COUNTRIES.iter() {
         info!("Loading ships for country {}...", country);
-        ships.append(&mut get_country_ships(country));
+        for entry in get_country_ships(source, country)? {
+            add_entry(&mut ships, entry);
+        }
     }
     info!("Found {} ships", ships.len());
-    ships
+    Ok(ships)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::FixtureSource;
+    use crate::version::GameVersion;
+    use crate::test_util::read_fixture;
+
+    fn listing() -> String {
+        read_fixture("vehicles_usa.html")
+    }
+
+    fn find<'a>(entries: &'a [ShipListEntry], id: &str) -> &'a ShipListEntry {
+        entries.iter().find(|entry| { entry.id == id }).unwrap()
+    }
+
+    #[test]
+    fn parses_listing() {
+        let entries = parse_country_page(&listing(), "usa");
+        let ids: Vec<&str> = entries.iter().map(|entry| { entry.id.as_str() }).collect();
+        assert_eq!(ids, vec!["pasc006", "pasc008", "pasb018", "pasd014"]);
+        assert!(entries.iter().all(|entry| { entry.nation == "usa" }));
+
+        // Linked twice, once without any text
+        let dallas = find(&entries, "pasc006");
+        assert_eq!(dallas.name.as_deref(), Some("Dallas"));
+        assert_eq!(dallas.tier, Some(6));
+        assert_eq!(dallas.class, Some(ShipClass::Cruiser));
+
+        let pensacola = find(&entries, "pasc008");
+        assert_eq!(pensacola.name.as_deref(), Some("Pensacola"));
+        assert_eq!(pensacola.tier, Some(8));
+        assert_eq!(pensacola.class, Some(ShipClass::Cruiser));
+
+        let iowa = find(&entries, "pasb018");
+        assert_eq!(iowa.name.as_deref(), Some("Iowa"));
+        assert_eq!(iowa.tier, Some(9));
+        assert_eq!(iowa.class, Some(ShipClass::Battleship));
+
+        let gearing = find(&entries, "pasd014");
+        assert_eq!(gearing.name.as_deref(), Some("Gearing"));
+        assert_eq!(gearing.tier, None);
+        assert_eq!(gearing.class, Some(ShipClass::Destroyer));
+        assert!(!gearing.matches(Some(10), None));
+        assert!(gearing.matches(None, Some(ShipClass::Destroyer)));
+    }
+
+    #[test]
+    fn ship_list_is_deduplicated_across_nations() {
+        let mut source = FixtureSource::new(GameVersion::current());
+        for country in COUNTRIES.iter() {
+            source.insert(&country_url(country), "<html></html>".to_string());
+        }
+        source.insert(&country_url("usa"), listing());
+        source.insert(&country_url("europe"), r#"<a href="/games/worldofwarships/vehicles/pasd014">X Gearing</a>"#.to_string());
+
+        let ships = get_ship_list(&source).unwrap();
+        assert_eq!(ships.len(), 4);
+        let gearing = find(&ships, "pasd014");
+        assert_eq!(gearing.nation, "usa");
+        assert_eq!(gearing.tier, Some(10));
+    }
 }
@@ -0,0 +1,324 @@
+//! Optional SQLite copy of the ships database, for asking questions like
+//! "every tier 8 cruiser with a belt over 100mm" in SQL. Only built with the
+//! `sqlite` feature.
+use cgmath::InnerSpace;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::gun::{Ship, ShipClass, ShipConfiguration, AmmoType, ArmorType, HitLocationType, SCHEMA_VERSION};
+use crate::version::GameVersion;
+
+const SCHEMA: &str = "
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE ships (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    nation TEXT NOT NULL,
+    tier INTEGER NOT NULL,
+    class TEXT NOT NULL,
+    -- The whole bincode Ship, for loading it back
+    data BLOB NOT NULL
+);
+CREATE TABLE configurations (
+    ship_id TEXT NOT NULL REFERENCES ships(id),
+    config INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    artillery TEXT, -- the artillery module, e.g. 'B_Artillery'
+    fire_control TEXT,
+    speed REAL NOT NULL, -- m/s
+    length REAL NOT NULL, -- m
+    health REAL NOT NULL,
+    torpedo_protection REAL NOT NULL, -- the fraction of torpedo damage the torpedo protection belt absorbs
+    PRIMARY KEY (ship_id, config)
+);
+CREATE TABLE hit_locations (
+    ship_id TEXT NOT NULL,
+    config INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL, -- e.g. 'Bow', 'Citadel', or the game's hlType for others
+    max_hp REAL NOT NULL,
+    share REAL NOT NULL, -- of the configuration's health
+    PRIMARY KEY (ship_id, config, name)
+);
+CREATE TABLE guns (
+    ship_id TEXT NOT NULL,
+    config INTEGER NOT NULL,
+    battery TEXT NOT NULL, -- 'main' or 'secondary'
+    gun INTEGER NOT NULL,
+    range REAL NOT NULL, -- m
+    reload REAL NOT NULL, -- s
+    barrels INTEGER NOT NULL,
+    -- The game's dispersion parameters, see ballistics::Dispersion
+    ideal_radius REAL NOT NULL,
+    min_radius REAL NOT NULL,
+    ideal_distance REAL NOT NULL,
+    taper_distance REAL NOT NULL,
+    radius_on_zero REAL NOT NULL,
+    radius_on_delim REAL NOT NULL,
+    radius_on_max REAL NOT NULL,
+    delim REAL NOT NULL,
+    max_range REAL NOT NULL, -- m
+    sigma REAL NOT NULL,
+    -- Dispersion radii at max range, in m
+    horizontal_at_max REAL NOT NULL,
+    vertical_at_max REAL NOT NULL,
+    PRIMARY KEY (ship_id, config, battery, gun)
+);
+CREATE TABLE ammo (
+    ship_id TEXT NOT NULL,
+    config INTEGER NOT NULL,
+    battery TEXT NOT NULL,
+    gun INTEGER NOT NULL,
+    ammo INTEGER NOT NULL,
+    type TEXT NOT NULL, -- 'HE', 'AP' or 'SAP'
+    damage REAL NOT NULL,
+    piercing REAL, -- mm, HE and SAP only
+    ricochet_at REAL, -- degrees, AP and SAP only
+    always_ricochet_at REAL, -- degrees, AP and SAP only
+    ap_normalization REAL, -- degrees
+    ap_detonator REAL, -- s
+    ap_detonator_threshold REAL, -- mm
+    mass REAL NOT NULL, -- kg
+    diameter REAL NOT NULL, -- m
+    muzzle_speed REAL NOT NULL, -- m/s
+    drag REAL NOT NULL,
+    krupp REAL NOT NULL,
+    PRIMARY KEY (ship_id, config, battery, gun, ammo)
+);
+CREATE TABLE torpedoes (
+    ship_id TEXT NOT NULL,
+    config INTEGER NOT NULL,
+    launcher INTEGER NOT NULL,
+    ammo INTEGER NOT NULL,
+    barrels INTEGER NOT NULL,
+    reload REAL NOT NULL, -- s
+    arc_from REAL NOT NULL, -- degrees from the bow
+    arc_to REAL NOT NULL,
+    damage REAL NOT NULL,
+    speed REAL NOT NULL, -- m/s
+    range REAL NOT NULL, -- m
+    flooding REAL NOT NULL, -- chance, 0 to 1
+    PRIMARY KEY (ship_id, config, launcher, ammo)
+);
+-- One row per armor type and thickness on each configuration
+CREATE TABLE armor_zones (
+    ship_id TEXT NOT NULL,
+    config INTEGER NOT NULL,
+    armor_type TEXT NOT NULL,
+    thickness REAL NOT NULL, -- mm
+    faces INTEGER NOT NULL,
+    area REAL NOT NULL, -- m^2
+    PRIMARY KEY (ship_id, config, armor_type, thickness)
+);
+";
+
+fn class_name(class: ShipClass) -> &'static str {
+    match class {
+        ShipClass::Destroyer => "destroyer",
+        ShipClass::Cruiser => "cruiser",
+        ShipClass::Battleship => "battleship",
+        ShipClass::AircraftCarrier => "aircarrier",
+    }
+}
+
+fn insert_configuration(tx: &Connection, ship: &Ship, index: usize, config: &ShipConfiguration) -> Result<()> {
+    let index = index as i64;
+    tx.execute(
+        "INSERT INTO configurations (ship_id, config, name, artillery, fire_control, speed, length, health, torpedo_protection) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![ship.id, index, config.name, config.modules.get("artillery"), config.modules.get("fireControl"), config.speed, config.length,
+                config.hit_points.health, config.torpedo_protection],
+    )?;
+    for section in config.hit_points.sections.iter() {
+        let kind = match &section.kind {
+            HitLocationType::Other(kind) => kind.clone(),
+            kind => format!("{:?}", kind),
+        };
+        tx.execute(
+            "INSERT INTO hit_locations (ship_id, config, name, kind, max_hp, share) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![ship.id, index, section.name, kind, section.max_hp, config.hit_points.share(section)],
+        )?;
+    }
+    let batteries = config.artillery.iter().map(|gun| { ("main", gun) }).enumerate()
+        .chain(config.secondaries.iter().map(|gun| { ("secondary", gun) }).enumerate());
+    for (gun_index, (battery, gun)) in batteries {
+        let gun_index = gun_index as i64;
+        let d = &gun.dispersion;
+        tx.execute(
+            "INSERT INTO guns (ship_id, config, battery, gun, range, reload, barrels, ideal_radius, min_radius, ideal_distance, taper_distance,
+                               radius_on_zero, radius_on_delim, radius_on_max, delim, max_range, sigma, horizontal_at_max, vertical_at_max)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+            params![ship.id, index, battery, gun_index, gun.range, gun.reload, gun.barrels as i64, d.ideal_radius, d.min_radius, d.ideal_distance, d.taper_distance,
+                    d.radius_on_zero, d.radius_on_delim, d.radius_on_max, d.delim, d.max_range, d.sigma,
+                    d.horizontal(d.max_range), d.vertical(d.max_range)],
+        )?;
+        for (ammo_index, ammo) in gun.ammo.iter().enumerate() {
+            let (kind, damage, piercing, ricochet, normalization, detonator, threshold) = match &ammo.bullet {
+                AmmoType::He(he) => ("HE", he.damage, Some(he.piercing), None, None, None, None),
+                AmmoType::Ap(ap) => ("AP", ap.damage, None, Some(&ap.ricochet), Some(ap.normalization), Some(ap.detonator), Some(ap.detonator_threshold)),
+                AmmoType::Sap(sap) => ("SAP", sap.damage, Some(sap.piercing), Some(&sap.ricochet), None, None, None),
+            };
+            let b = &ammo.ballistics;
+            tx.execute(
+                "INSERT INTO ammo (ship_id, config, battery, gun, ammo, type, damage, piercing, ricochet_at, always_ricochet_at, ap_normalization,
+                                   ap_detonator, ap_detonator_threshold, mass, diameter, muzzle_speed, drag, krupp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+                params![ship.id, index, battery, gun_index, ammo_index as i64, kind, damage, piercing,
+                        ricochet.map(|r| { r.ricochet_at }), ricochet.map(|r| { r.always_ricochet_at }), normalization, detonator, threshold,
+                        b.mass, b.diameter, b.muzzle_speed, b.drag, b.krupp],
+            )?;
+        }
+    }
+
+    for (launcher_index, launcher) in config.torpedoes.iter().enumerate() {
+        for (ammo_index, torpedo) in launcher.ammo.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO torpedoes (ship_id, config, launcher, ammo, barrels, reload, arc_from, arc_to, damage, speed, range, flooding)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![ship.id, index, launcher_index as i64, ammo_index as i64, launcher.barrels as i64, launcher.reload,
+                        launcher.arc[0], launcher.arc[1], torpedo.damage, torpedo.speed, torpedo.range, torpedo.flooding],
+            )?;
+        }
+    }
+
+    // Sum up the faces of each armor type and thickness. Thicknesses are
+    // keyed in tenths of a millimeter so they can be ordered.
+    let mut zones: BTreeMap<(String, i64), (i64, f64)> = BTreeMap::new();
+    for face in config.geometry.iter() {
+        let area = 0.5 * (face.vertices[1] - face.vertices[0]).cross(face.vertices[2] - face.vertices[0]).magnitude();
+        let armor_type = match face.armor_type {
+            ArmorType::Normal => "normal",
+            ArmorType::Citadel => "citadel",
+            ArmorType::TorpedoProtectionBelt => "torpedo_protection_belt",
+        };
+        let zone = zones.entry((armor_type.to_string(), (face.thickness * 10.0).round() as i64)).or_insert((0, 0.0));
+        zone.0 += 1;
+        zone.1 += area;
+    }
+    for ((armor_type, thickness), (faces, area)) in zones {
+        tx.execute(
+            "INSERT INTO armor_zones (ship_id, config, armor_type, thickness, faces, area) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![ship.id, index, armor_type, thickness as f64 / 10.0, faces, area],
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the vehicles to a new SQLite database at `path`, replacing it if
+/// it exists.
+pub fn export_sqlite<P: AsRef<Path>>(path: P, version: &GameVersion, vehicles: &[Ship]) -> Result<()> {
+    let path = path.as_ref();
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let mut conn = Connection::open(path)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    tx.execute("INSERT INTO meta (key, value) VALUES ('schema_version', ?1)", params![SCHEMA_VERSION.to_string()])?;
+    tx.execute("INSERT INTO meta (key, value) VALUES ('game_version', ?1)", params![version.to_string()])?;
+    for ship in vehicles {
+        let data = bincode::serialize(ship)?;
+        tx.execute(
+            "INSERT INTO ships (id, name, nation, tier, class, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![ship.id, ship.name, ship.nation, ship.tier as i64, class_name(ship.class), data],
+        )?;
+        for (index, config) in ship.configurations.iter().enumerate() {
+            insert_configuration(&tx, ship, index, config)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// A database written by `export_sqlite`, opened for loading ships back
+pub struct SqliteShips {
+    conn: Connection,
+}
+
+impl SqliteShips {
+    /// Opens the database, checking that its ships can be decoded by this
+    /// build and are for the given game version
+    pub fn open<P: AsRef<Path>>(path: P, version: &GameVersion) -> Result<SqliteShips> {
+        let path = path.as_ref();
+        let bad_database = |reason: String| {
+            Error::Decode { url: path.display().to_string(), reason: reason }
+        };
+
+        let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let meta = |key: &str| -> Result<String> {
+            Ok(conn.query_row("SELECT value FROM meta WHERE key = ?1", params![key], |row| { row.get(0) })?)
+        };
+        let schema_version = meta("schema_version")?;
+        if schema_version != SCHEMA_VERSION.to_string() {
+            return Err(bad_database(format!("Database has schema version {}, not {}", schema_version, SCHEMA_VERSION)));
+        }
+        let game_version = meta("game_version")?;
+        if game_version != version.to_string() {
+            return Err(bad_database(format!("Database is for game version {}, not {}", game_version, version)));
+        }
+        Ok(SqliteShips { conn: conn })
+    }
+
+    /// Loads every ship
+    pub fn ships(&self) -> Result<Vec<Ship>> {
+        let mut statement = self.conn.prepare("SELECT data FROM ships ORDER BY rowid")?;
+        let blobs = statement.query_map(NO_PARAMS, |row| { row.get::<_, Vec<u8>>(0) })?;
+        let mut vehicles = vec!();
+        for blob in blobs {
+            vehicles.push(bincode::deserialize(&blob?)?);
+        }
+        Ok(vehicles)
+    }
+
+    /// Loads a single ship by id, without reading the rest of the database
+    pub fn get(&self, id: &str) -> Result<Option<Ship>> {
+        let blob: Option<Vec<u8>> = self.conn.query_row("SELECT data FROM ships WHERE id = ?1 COLLATE NOCASE", params![id], |row| { row.get(0) }).optional()?;
+        match blob {
+            Some(blob) => Ok(Some(bincode::deserialize(&blob)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Loads every ship from a database written by `export_sqlite` for `version`
+pub fn import_sqlite<P: AsRef<Path>>(path: P, version: &GameVersion) -> Result<Vec<Ship>> {
+    SqliteShips::open(path, version)?.ships()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ship_parser::download_vehicle;
+    use crate::test_util::{fixtures, TempDir};
+
+    #[test]
+    fn round_trip() {
+        let ship = download_vehicle(&fixtures(), "pasd014").unwrap().unwrap();
+        let dir = TempDir::new("sqlite");
+        let path = dir.join("ships.sqlite");
+        let (configurations, faces) = (ship.configurations.len(), ship.configurations[0].geometry.len());
+        export_sqlite(&path, &GameVersion::current(), &[ship]).unwrap();
+
+        let database = SqliteShips::open(&path, &GameVersion::current()).unwrap();
+        let loaded = database.get("PASD014").unwrap().unwrap();
+        assert_eq!(loaded.id, "pasd014");
+        assert_eq!(loaded.configurations.len(), configurations);
+        assert_eq!(loaded.configurations[0].geometry.len(), faces);
+        assert!(database.get("pasb006").unwrap().is_none());
+        assert_eq!(import_sqlite(&path, &GameVersion::current()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_other_game_versions() {
+        let dir = TempDir::new("sqlite");
+        let path = dir.join("ships.sqlite");
+        export_sqlite(&path, &GameVersion::current(), &[]).unwrap();
+        assert!(SqliteShips::open(&path, &GameVersion::parse("0.9.4").unwrap()).is_err());
+        assert!(import_sqlite(&path, &GameVersion::parse("0.9.4").unwrap()).is_err());
+    }
+}
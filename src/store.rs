@@ -0,0 +1,222 @@
+use serde_derive::{Serialize, Deserialize};
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::gun::{Ship, ShipClass, SCHEMA_VERSION};
+use crate::lookup::find_by_id;
+use crate::util::{file_header, read_file_header, sha256, write_atomically};
+use crate::version::GameVersion;
+
+/// Store files start with this, followed by the little-endian format version,
+/// the little-endian length of the index, the bincode `StoreIndex`, and then
+/// each ship's bincode blob.
+const STORE_MAGIC: &[u8; 8] = b"WOWSSTOR";
+const STORE_FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8 + 4 + 8;
+
+/// Where to find one ship in a store, plus enough about it to pick ships
+/// without loading any
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoreEntry {
+    pub id: String,
+    pub name: String,
+    pub tier: usize,
+    pub class: ShipClass,
+    pub nation: String,
+    /// Offset of the ship's blob from the end of the index
+    offset: u64,
+    length: u64,
+    /// Hex SHA-256 of the blob
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreIndex {
+    schema_version: u32,
+    game_version: GameVersion,
+    entries: Vec<StoreEntry>,
+}
+
+enum Backing {
+    #[cfg(not(feature = "mmap"))]
+    File(std::sync::Mutex<fs::File>),
+    #[cfg(feature = "mmap")]
+    Map(memmap::Mmap),
+}
+
+/// The ships database split into an index and per-ship blobs, so that opening
+/// it only reads the index and each ship is decoded when it's asked for.
+///
+/// With the `mmap` feature, the file is memory mapped rather than read.
+pub struct ShipStore {
+    path: String,
+    entries: Vec<StoreEntry>,
+    /// Where the blobs start
+    data_start: u64,
+    backing: Backing,
+}
+
+impl ShipStore {
+    /// Opens a store, reading only its index. The store has to have been
+    /// written for this schema and game version.
+    pub fn open<P: AsRef<Path>>(path: P, version: &GameVersion) -> Result<ShipStore> {
+        let path = path.as_ref();
+        let bad_store = |reason: String| {
+            Error::Decode { url: path.display().to_string(), reason: reason }
+        };
+
+        let mut f = fs::File::open(path)?;
+        read_file_header(&mut f, path, "ship store", STORE_MAGIC, STORE_FORMAT_VERSION)?;
+        let mut index_len = [0; 8];
+        f.read_exact(&mut index_len).map_err(|_| { bad_store("Missing the index length".to_string()) })?;
+        let index_len = u64::from_le_bytes(index_len);
+        // Check the length before allocating for it, so a corrupt store can't
+        // ask for more memory than the file could hold
+        if index_len > f.metadata()?.len().saturating_sub(HEADER_LEN) {
+            return Err(bad_store(format!("The index ({} bytes) runs past the end of the file", index_len)));
+        }
+
+        let mut index = vec![0; index_len as usize];
+        f.read_exact(&mut index)?;
+        let index: StoreIndex = bincode::deserialize(&index).map_err(|e| { bad_store(e.to_string()) })?;
+        if index.schema_version != SCHEMA_VERSION {
+            return Err(bad_store(format!(
+                "Store has schema version {}, not {}",
+                index.schema_version, SCHEMA_VERSION
+            )));
+        }
+        if &index.game_version != version {
+            return Err(bad_store(format!(
+                "Store is for game version {}, not {}",
+                index.game_version, version
+            )));
+        }
+
+        #[cfg(feature = "mmap")]
+        let backing = Backing::Map(unsafe { memmap::Mmap::map(&f)? });
+        #[cfg(not(feature = "mmap"))]
+        let backing = Backing::File(std::sync::Mutex::new(f));
+
+        Ok(ShipStore {
+            path: path.display().to_string(),
+            entries: index.entries,
+            data_start: HEADER_LEN + index_len,
+            backing: backing,
+        })
+    }
+
+    pub fn entries(&self) -> &[StoreEntry] {
+        &self.entries
+    }
+
+    fn read_blob(&self, entry: &StoreEntry) -> Result<Vec<u8>> {
+        let start = self.data_start + entry.offset;
+        match &self.backing {
+            #[cfg(not(feature = "mmap"))]
+            Backing::File(f) => {
+                let mut f = f.lock().unwrap();
+                let mut blob = vec![0; entry.length as usize];
+                f.seek(std::io::SeekFrom::Start(start))?;
+                f.read_exact(&mut blob)?;
+                Ok(blob)
+            }
+            #[cfg(feature = "mmap")]
+            Backing::Map(map) => {
+                let end = start + entry.length;
+                if end > map.len() as u64 {
+                    return Err(Error::Decode { url: self.path.clone(), reason: format!("{} runs past the end of the file", entry.id) });
+                }
+                Ok(map[start as usize..end as usize].to_vec())
+            }
+        }
+    }
+
+    /// Loads the ship with the given id, if the store has it
+    pub fn get(&self, id: &str) -> Result<Option<Ship>> {
+        match find_by_id(&self.entries, id) {
+            Some(entry) => Ok(Some(self.load(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the ship for one of this store's index entries
+    pub fn load(&self, entry: &StoreEntry) -> Result<Ship> {
+        let blob = self.read_blob(entry)?;
+        if sha256(&blob) != entry.sha256 {
+            return Err(Error::Decode { url: self.path.clone(), reason: format!("Checksum mismatch for {}", entry.id) });
+        }
+        bincode::deserialize(&blob).map_err(|e| {
+            Error::Decode { url: self.path.clone(), reason: format!("{}: {}", entry.id, e) }
+        })
+    }
+}
+
+/// Writes the vehicles out as a store. Like the database, it's written next
+/// to the old file and renamed over it.
+pub fn write_store<P: AsRef<Path>>(path: P, version: &GameVersion, vehicles: &[Ship]) -> Result<()> {
+    let mut entries = vec!();
+    let mut blobs = vec!();
+    for ship in vehicles {
+        let blob = bincode::serialize(ship)?;
+        entries.push(StoreEntry {
+            id: ship.id.clone(),
+            name: ship.name.clone(),
+            tier: ship.tier,
+            class: ship.class,
+            nation: ship.nation.clone(),
+            offset: blobs.len() as u64,
+            length: blob.len() as u64,
+            sha256: sha256(&blob),
+        });
+        blobs.extend_from_slice(&blob);
+    }
+    let index = bincode::serialize(&StoreIndex {
+        schema_version: SCHEMA_VERSION,
+        game_version: version.clone(),
+        entries: entries,
+    })?;
+
+    let mut data = file_header(STORE_MAGIC, STORE_FORMAT_VERSION);
+    data.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    data.extend_from_slice(&index);
+    data.extend_from_slice(&blobs);
+
+    write_atomically(path, &data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ship_parser::download_vehicle;
+    use crate::test_util::{fixtures, TempDir};
+
+    #[test]
+    fn round_trip() {
+        let ship = download_vehicle(&fixtures(), "pasd014").unwrap().unwrap();
+        let configurations = ship.configurations.len();
+        let dir = TempDir::new("store");
+        let path = dir.join("ships.store");
+        write_store(&path, &GameVersion::current(), &[ship]).unwrap();
+
+        let store = ShipStore::open(&path, &GameVersion::current()).unwrap();
+        assert_eq!(store.entries().len(), 1);
+        assert_eq!(store.entries()[0].id, "pasd014");
+        let loaded = store.get("PASD014").unwrap().unwrap();
+        assert_eq!(loaded.configurations.len(), configurations);
+        assert!(store.get("pasb006").unwrap().is_none());
+        assert!(ShipStore::open(&path, &GameVersion::parse("0.9.4").unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_index_lengths_past_the_end() {
+        let dir = TempDir::new("store");
+        let path = dir.join("ships.store");
+        let mut data = file_header(STORE_MAGIC, STORE_FORMAT_VERSION);
+        data.extend_from_slice(&u64::max_value().to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        assert!(ShipStore::open(&path, &GameVersion::current()).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Helpers shared by the tests
+
+use cgmath::Point3;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::datasource::FixtureSource;
+use crate::gun::{ArmorFace, ArmorType, HitPoints, ShipConfiguration};
+use crate::version::GameVersion;
+
+static DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// The pages in `fixtures/manifest.json`
+pub fn fixtures() -> FixtureSource {
+    FixtureSource::from_dir(fixtures_dir(), GameVersion::current()).unwrap()
+}
+
+pub fn read_fixture(file: &str) -> String {
+    fs::read_to_string(fixtures_dir().join(file)).unwrap()
+}
+
+/// A target which is one big plate of armor in the z = 0 plane, so that shells
+/// fired along +z from the origin hit it head on
+pub fn plate(thickness: f64, armor_type: ArmorType) -> ShipConfiguration {
+    let face = ArmorFace::new([
+        Point3::new(-100.0, -100.0, 0.0),
+        Point3::new(100.0, -100.0, 0.0),
+        Point3::new(0.0, 100.0, 0.0),
+    ], thickness, armor_type);
+    ShipConfiguration::new(vec!(), vec!(), vec!(), 0, Arc::new(vec![face]), 0.0, 0.0, "Plate".to_string(), BTreeMap::new(), HitPoints::default(), 0.0)
+}
+
+/// A fresh, empty directory which is removed when dropped, so it's cleaned up
+/// even if the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("wows_armor_{}_{}_{}", name, std::process::id(), DIR_COUNTER.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
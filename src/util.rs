@@ -0,0 +1,81 @@
+use sha2::{Sha256, Digest};
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+
+/// Used to give every in-progress write its own temporary file
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hex SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data)[..])
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it into place,
+/// so that readers never see a partial file and two writers can't interleave
+/// their writes
+pub fn write_atomically<P: AsRef<Path>>(path: P, data: &[u8]) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.{}.tmp", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let tmp = Path::new(&tmp);
+    if let Err(e) = fs::write(tmp, data).and_then(|_| { fs::rename(tmp, path) }) {
+        let _ = fs::remove_file(tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Our binary files start with an 8 byte magic number followed by the
+/// little-endian format version
+pub fn file_header(magic: &[u8; 8], format_version: u32) -> Vec<u8> {
+    let mut header = magic.to_vec();
+    header.extend_from_slice(&format_version.to_le_bytes());
+    header
+}
+
+/// Reads and checks a header written by `file_header`. `kind` names the kind
+/// of file for the errors, e.g. "ship store".
+pub fn read_file_header<R: Read>(r: &mut R, path: &Path, kind: &str, magic: &[u8; 8], format_version: u32) -> Result<()> {
+    let bad_file = |reason: String| {
+        Error::Decode { url: path.display().to_string(), reason: reason }
+    };
+
+    let mut header = [0; 12];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => { return Err(bad_file(format!("Not a {}", kind))); }
+        Err(e) => { return Err(e.into()); }
+    }
+    if &header[..8] != magic {
+        return Err(bad_file(format!("Not a {}", kind)));
+    }
+    let mut found_version = [0; 4];
+    found_version.copy_from_slice(&header[8..]);
+    let found_version = u32::from_le_bytes(found_version);
+    if found_version != format_version {
+        return Err(bad_file(format!(
+            "Format version {} of the {} isn't supported (expected {})",
+            found_version, kind, format_version
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_headers() {
+        let path = Path::new("test.dat");
+        let header = file_header(b"WOWSTEST", 3);
+        assert!(read_file_header(&mut &header[..], path, "test file", b"WOWSTEST", 3).is_ok());
+        assert!(read_file_header(&mut &header[..], path, "test file", b"WOWSTEST", 4).is_err());
+        assert!(read_file_header(&mut &header[..], path, "test file", b"WOWSELSE", 3).is_err());
+        assert!(read_file_header(&mut &header[..6], path, "test file", b"WOWSTEST", 3).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use serde_derive::{Serialize, Deserialize};
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::download::fetch_uncached;
+use crate::error::{Error, Result};
+
+const SITE_URL: &str = "https://gamemodels3d.com/games/worldofwarships/";
+
+/// Where the last detected version is remembered, for runs which can't ask
+/// the site
+const DETECTED_VERSION_PATH: &str = "cache/detected_version";
+
+/// What a game version looks like, e.g. "0.9.4" or "0.9.4.1"
+const VERSION_PATTERN: &str = r"\d+(?:\.\d+){2,3}";
+
+/// A game version (e.g. "0.9.4") whose data we're working with, or "current"
+/// for whatever the site currently serves.
+///
+/// Each version gets its own cache directory and ships database, so that
+/// snapshots from different patches can live next to each other.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GameVersion(String);
+
+impl GameVersion {
+    fn new(version: &str) -> GameVersion {
+        GameVersion(version.to_string())
+    }
+
+    /// Checks that `version` is "current" or looks like a game version, since
+    /// it ends up in file paths and URLs
+    pub fn parse(version: &str) -> Result<GameVersion> {
+        let re = Regex::new(&format!("^{}$", VERSION_PATTERN)).unwrap();
+        if version == "current" || re.is_match(version) {
+            Ok(GameVersion::new(version))
+        } else {
+            Err(Error::UnknownValue { path: "game version".to_string(), value: version.to_string() })
+        }
+    }
+
+    pub fn current() -> GameVersion {
+        GameVersion::new("current")
+    }
+
+    pub fn is_current(&self) -> bool {
+        self.0 == "current"
+    }
+
+    /// Base URL for this version's data files, e.g. armor models
+    pub fn data_url(&self) -> String {
+        format!("{}data/{}", SITE_URL, self.0)
+    }
+
+    /// "current" uses `cache/` directly, so caches from before versioning
+    /// keep working.
+    pub fn cache_dir(&self) -> PathBuf {
+        if self.is_current() {
+            PathBuf::from("cache/")
+        } else {
+            PathBuf::from("cache/").join(&self.0)
+        }
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        if self.is_current() {
+            PathBuf::from("ships.dat")
+        } else {
+            PathBuf::from(format!("ships-{}.dat", self.0))
+        }
+    }
+
+    /// The ships database split up for loading one ship at a time
+    pub fn store_path(&self) -> PathBuf {
+        self.database_path().with_extension("store")
+    }
+
+    /// Asks the site which game version it's currently serving. This always
+    /// goes to the network, so it doesn't work in offline mode.
+    pub fn detect() -> Result<GameVersion> {
+        let page = fetch_uncached(SITE_URL)?;
+        let re = Regex::new(&format!("data/({})/", VERSION_PATTERN)).unwrap();
+        match re.captures(&page) {
+            Some(capture) => Ok(GameVersion::new(&capture[1])),
+            None => Err(Error::Decode {
+                url: SITE_URL.to_string(),
+                reason: "Couldn't find a game version".to_string(),
+            }),
+        }
+    }
+
+    /// Records this as the version the site was last seen serving
+    pub fn remember(&self) -> Result<()> {
+        fs::create_dir_all("cache/")?;
+        fs::write(DETECTED_VERSION_PATH, &self.0)?;
+        Ok(())
+    }
+
+    /// The version `remember` last recorded, if any
+    pub fn last_detected() -> Option<GameVersion> {
+        let version = fs::read_to_string(DETECTED_VERSION_PATH).ok()?;
+        GameVersion::parse(version.trim()).ok()
+    }
+}
+
+impl fmt::Display for GameVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accepts_versions() {
+        assert!(GameVersion::parse("current").unwrap().is_current());
+        assert_eq!(GameVersion::parse("0.9.4").unwrap().database_path(), PathBuf::from("ships-0.9.4.dat"));
+        assert_eq!(GameVersion::parse("0.9.4.1").unwrap().cache_dir(), PathBuf::from("cache/0.9.4.1"));
+    }
+
+    #[test]
+    fn parse_rejects_paths() {
+        for version in &["", "detect", "../x", "0.9.4/../../x", "0.9", "/tmp/0.9.4", "0.9.4 "] {
+            assert!(GameVersion::parse(version).is_err(), "{:?} was accepted", version);
+        }
+    }
+}